@@ -0,0 +1,49 @@
+use crate::error::Error;
+use crate::instrument::Instrument;
+use crate::orderbook::{Exchange, InTick};
+use crate::websocket::{self, WsStream};
+use std::time::Duration;
+use tungstenite::Message;
+
+/// A venue the `Connector` can stream order books from.
+///
+/// Each adapter owns whatever per-connection state it needs to turn raw websocket
+/// messages into `InTick`s, so adding a venue only means implementing this trait.
+#[tonic::async_trait]
+pub(crate) trait ExchangeAdapter: Send {
+    fn exchange(&self) -> Exchange;
+
+    /// Asks the venue how it lists the instruments, failing with `Error::NotListed`
+    /// for one it doesn't. Keeps the derived venue symbols if the venue can't be
+    /// reached.
+    async fn resolve(&mut self) -> Result<(), Error>;
+
+    /// Opens the websocket connection to the venue.
+    async fn connect(&mut self) -> Result<WsStream, Error>;
+
+    /// Sends whatever the venue needs before it starts streaming the order book.
+    async fn subscribe(&mut self, ws_stream: &mut WsStream) -> Result<(), Error>;
+
+    /// Instruments whose subscription the venue hasn't confirmed since `subscribe`.
+    fn unconfirmed(&self) -> Vec<Instrument>;
+
+    /// Returns an `InTick` if the message carries order book data.
+    fn parse(&mut self, msg: Message) -> Result<Option<InTick>, Error>;
+
+    /// Whether the connection should be replaced, because the venue asked for it,
+    /// is about to drop it, or a book needs a fresh snapshot.
+    fn reconnect_due(&self) -> bool {
+        false
+    }
+
+    /// How long the venue may stay silent before the connection is given up, for
+    /// venues which send heartbeats.
+    fn idle_timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Gracefully closes the connection by Close-handshake procedure.
+    async fn close(&mut self, ws_stream: &mut WsStream) {
+        websocket::close(ws_stream).await;
+    }
+}
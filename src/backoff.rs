@@ -0,0 +1,39 @@
+use rand::Rng;
+use std::time::Duration;
+
+const INITIAL_DELAY: Duration = Duration::from_millis(500);
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Exponential backoff with full jitter, used to space out reconnect attempts.
+#[derive(Debug)]
+pub(crate) struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub(crate) fn new() -> Backoff {
+        Backoff {
+            initial: INITIAL_DELAY,
+            max: MAX_DELAY,
+            attempt: 0,
+        }
+    }
+
+    /// Returns a random delay between zero and the current exponential ceiling,
+    /// then doubles the ceiling up to `max`.
+    pub(crate) fn next_delay(&mut self) -> Duration {
+        let ceiling = self.initial
+            .checked_mul(2u32.saturating_pow(self.attempt))
+            .map_or(self.max, |d| d.min(self.max));
+        self.attempt = self.attempt.saturating_add(1);
+
+        let millis = ceiling.as_millis() as u64;
+        Duration::from_millis(rand::thread_rng().gen_range(0..=millis))
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
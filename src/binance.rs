@@ -1,19 +1,242 @@
+use chrono::{DateTime, TimeZone, Utc};
+use crate::adapter::ExchangeAdapter;
 use crate::error::Error;
-use crate::orderbook::{self, Exchange, InTick, ToLevel, ToLevels, ToTick};
-use crate::{BINANCE_WS_URL, DEPTH, websocket};
-use log::{debug};
+use crate::instrument::{self, Aliases, Instrument, VenueInstrument};
+use crate::metrics;
+use crate::orderbook::{self, Exchange, InTick, OrderDepthsMap, TickTime, ToLevel, ToLevels, ToTick};
+use crate::{BookStream, MAX_DEPTH, websocket};
+use log::{debug, info, warn};
 use rust_decimal::Decimal;
 use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
 use tungstenite::Message;
 
+/// Deepest snapshot the REST api hands out.
+const SNAPSHOT_LIMIT: usize = 5000;
+
+/// Deepest partial book stream available.
+const PARTIAL_DEPTH: usize = 20;
+
+/// Binance drops connections after 24 hours, they're replaced a bit before.
+const MAX_CONNECTION_AGE: Duration = Duration::from_secs(23 * 60 * 60 + 30 * 60);
+
+/// Assets Binance names differently, none so far.
+const ALIASES: Aliases = &[];
+
+pub(crate) struct Adapter {
+    /// Instruments keyed by the name of their streams, e.g. `ethbtc` -> `ETH/BTC`.
+    symbols: HashMap<String, VenueInstrument>,
+
+    /// Instruments subscribed to that didn't stream yet.
+    pending: Vec<Instrument>,
+
+    /// When the current connection was subscribed.
+    subscribed_at: Option<Instant>,
+
+    stream: BookStream,
+
+    /// Streams `@bookTicker`, the best bid and ask as they change, instead of
+    /// the book.
+    bbo: bool,
+
+    books: HashMap<String, LocalBook>,
+    url: String,
+
+    /// REST endpoint, e.g. `BINANCE_REST_URL`.
+    rest_url: String,
+}
+
+impl Adapter {
+    pub(crate) fn new(instruments: &[Instrument], stream: BookStream, bbo: bool, url: &str, rest_url: &str) -> Adapter {
+        let symbols = instruments.iter()
+            .map(|i| VenueInstrument::new(i, venue_symbol(i)))
+            .map(|i| (i.venue_symbol.to_lowercase(), i))
+            .collect();
+        Adapter {
+            symbols,
+            pending: vec![],
+            subscribed_at: None,
+            stream,
+            bbo,
+            books: HashMap::new(),
+            url: url.to_string(),
+            rest_url: rest_url.to_string(),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl ExchangeAdapter for Adapter {
+    fn exchange(&self) -> Exchange {
+        Exchange::Binance
+    }
+
+    async fn resolve(&mut self) -> Result<(), Error> {
+        let client = instrument::listing_client()?;
+        for instrument in self.symbols.values_mut() {
+            match listing(&client, &self.rest_url, &instrument.venue_symbol).await {
+                Ok(Some(listing)) if listing.status == "TRADING" => {
+                    for filter in listing.filters {
+                        match filter {
+                            Filter::Price { tick_size } => instrument.tick_size = Some(tick_size.normalize()),
+                            Filter::LotSize { step_size } => instrument.lot_size = Some(step_size.normalize()),
+                            Filter::Other => {},
+                        }
+                    }
+                    info!("Binance lists {}", instrument);
+                },
+                Ok(_) => return Err(Error::NotListed {
+                    exchange: Exchange::Binance,
+                    instrument: instrument.instrument.clone(),
+                }),
+                Err(e) => warn!("Failed to look up {} on Binance, streaming {} unchecked: {:?}",
+                    instrument.symbol(), instrument.venue_symbol, e),
+            }
+        }
+        Ok(())
+    }
+
+    async fn connect(&mut self) -> Result<websocket::WsStream, Error> {
+        let venue_symbols: Vec<&String> = self.symbols.keys().collect();
+        connect(&self.url, &venue_symbols, self.stream, self.bbo).await
+    }
+
+    /// Binance subscribes through the stream names in the url. The diff stream
+    /// additionally needs a snapshot per symbol to apply the buffered updates on.
+    async fn subscribe(&mut self, _ws_stream: &mut websocket::WsStream) -> Result<(), Error> {
+        self.pending = self.symbols.values().map(|i| i.instrument.clone()).collect();
+        self.subscribed_at = Some(Instant::now());
+        self.books.clear();
+        if self.stream == BookStream::Diff && !self.bbo {
+            for (venue_symbol, instrument) in &self.symbols {
+                let book = LocalBook::new(&instrument.symbol(), snapshot(&self.rest_url, &instrument.venue_symbol).await?);
+                self.books.insert(venue_symbol.clone(), book);
+            }
+        }
+        Ok(())
+    }
+
+    /// Binance doesn't answer for a stream it doesn't have, it just stays silent,
+    /// so a subscription is only confirmed by its first tick.
+    fn unconfirmed(&self) -> Vec<Instrument> {
+        self.pending.clone()
+    }
+
+    fn parse(&mut self, msg: Message) -> Result<Option<InTick>, Error> {
+        let tick = match (self.bbo, self.stream) {
+            (true, _) => parse_ticker(msg, &self.symbols),
+            (false, BookStream::Partial) => parse(msg, &self.symbols),
+            (false, BookStream::Diff) => parse_diff(msg, &mut self.books),
+        }?;
+        if let Some(t) = &tick {
+            if !self.pending.is_empty() {
+                self.pending.retain(|i| i.to_string() != t.symbol);
+            }
+        }
+        Ok(tick)
+    }
+
+    /// Binance also pings every few minutes and drops connections that don't pong,
+    /// tungstenite answers those on its own.
+    fn reconnect_due(&self) -> bool {
+        self.subscribed_at.is_some_and(|at| at.elapsed() > MAX_CONNECTION_AGE)
+    }
+}
+
+/// The answer of `/exchangeInfo`, listing the instruments asked for.
+#[derive(Debug, Deserialize)]
+struct ExchangeInfo {
+    symbols: Vec<SymbolInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SymbolInfo {
+    /// `TRADING` unless the instrument is halted or delisted.
+    status: String,
+    filters: Vec<Filter>,
+}
+
+/// The rules an order of an instrument must follow, of which the increments matter.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "filterType")]
+enum Filter {
+    #[serde(rename = "PRICE_FILTER")]
+    Price {
+        #[serde(rename = "tickSize")]
+        tick_size: Decimal,
+    },
+
+    #[serde(rename = "LOT_SIZE")]
+    LotSize {
+        #[serde(rename = "stepSize")]
+        step_size: Decimal,
+    },
+
+    #[serde(other)]
+    Other,
+}
+
+/// Envelope of every message on a combined stream.
+#[derive(Debug, Deserialize, PartialEq)]
+struct Combined<T> {
+    stream: String,
+    data: T,
+}
+
+impl<T> Combined<T> {
+    /// The Binance symbol the stream is for, e.g. `ethbtc` of `ethbtc@depth10@100ms`.
+    fn venue_symbol(&self) -> &str {
+        self.stream.split('@').next().unwrap_or_default()
+    }
+}
+
 #[derive(Debug, Deserialize, PartialEq)]
 struct Event {
     #[serde(rename = "lastUpdateId")]
-    last_update_id: usize,
+    last_update_id: u64,
     bids: Vec<Level>,
     asks: Vec<Level>,
 }
 
+#[derive(Debug, Deserialize, PartialEq)]
+struct DiffEvent {
+    /// Event time in milliseconds since the Unix epoch.
+    #[serde(rename = "E")]
+    event_time: i64,
+
+    #[serde(rename = "U")]
+    first_update_id: u64,
+
+    #[serde(rename = "u")]
+    final_update_id: u64,
+
+    #[serde(rename = "b")]
+    bids: Vec<Level>,
+
+    #[serde(rename = "a")]
+    asks: Vec<Level>,
+}
+
+/// The best bid and ask of a symbol, sent whenever either changes.
+#[derive(Debug, Deserialize, PartialEq)]
+struct BookTicker {
+    #[serde(rename = "u")]
+    update_id: u64,
+
+    #[serde(rename = "b")]
+    bid_price: Decimal,
+
+    #[serde(rename = "B")]
+    bid_amount: Decimal,
+
+    #[serde(rename = "a")]
+    ask_price: Decimal,
+
+    #[serde(rename = "A")]
+    ask_amount: Decimal,
+}
+
 #[derive(Debug, Deserialize, PartialEq, Clone)]
 struct Level {
     price: Decimal,
@@ -28,25 +251,152 @@ impl ToLevel for Level {
 
 impl ToTick for Event {
 
-    fn maybe_to_tick(&self) -> Option<InTick> {
-        let bids = self.bids.to_levels(orderbook::Side::Bid, DEPTH);
-        let asks = self.asks.to_levels(orderbook::Side::Ask, DEPTH);
+    /// The partial book stream carries no event time, only the update id.
+    fn maybe_to_tick(&self, symbol: &str) -> Option<InTick> {
+        let bids = self.bids.to_levels(orderbook::Side::Bid, MAX_DEPTH);
+        let asks = self.asks.to_levels(orderbook::Side::Ask, MAX_DEPTH);
+        let time = TickTime::new(None, Some(self.last_update_id));
+
+        Some(InTick { exchange: Exchange::Binance, symbol: symbol.to_string(), bids, asks, time })
+    }
+}
+
+impl ToTick for BookTicker {
+
+    /// Like the partial book stream, the book ticker carries no event time.
+    fn maybe_to_tick(&self, symbol: &str) -> Option<InTick> {
+        let bids = vec![orderbook::Level::new(orderbook::Side::Bid, self.bid_price, self.bid_amount, Exchange::Binance)];
+        let asks = vec![orderbook::Level::new(orderbook::Side::Ask, self.ask_price, self.ask_amount, Exchange::Binance)];
+        let time = TickTime::new(None, Some(self.update_id));
+
+        Some(InTick { exchange: Exchange::Binance, symbol: symbol.to_string(), bids, asks, time })
+    }
+}
+
+impl DiffEvent {
+    fn event_time(&self) -> Option<DateTime<Utc>> {
+        Utc.timestamp_millis_opt(self.event_time).single()
+    }
+}
+
+/// The local book of the diff stream along with the id of the last update applied.
+#[derive(Debug)]
+struct LocalBook {
+    symbol: String,
+    last_update_id: u64,
+    synced: bool,
+    depths: OrderDepthsMap,
+}
+
+impl LocalBook {
+    fn new(symbol: &str, snapshot: Event) -> Self {
+        let mut depths = OrderDepthsMap::new();
+        depths.reset(
+            snapshot.bids.to_levels(orderbook::Side::Bid, SNAPSHOT_LIMIT),
+            snapshot.asks.to_levels(orderbook::Side::Ask, SNAPSHOT_LIMIT),
+        );
+        LocalBook {
+            symbol: symbol.to_string(),
+            last_update_id: snapshot.last_update_id,
+            synced: false,
+            depths,
+        }
+    }
+
+    /// Applies a diff update following Binance's rules for managing a local book:
+    /// updates older than the snapshot are dropped, the first one applied must
+    /// straddle the snapshot, and every following one must pick up where the
+    /// previous one ended.
+    fn apply(&mut self, e: DiffEvent) -> Result<Option<InTick>, Error> {
+        let next_id = self.last_update_id + 1;
+
+        if e.final_update_id < next_id {
+            return Ok(None)
+        }
+
+        let in_sequence = match self.synced {
+            false => e.first_update_id <= next_id,
+            true => e.first_update_id == next_id,
+        };
+        if !in_sequence {
+            return Err(Error::OutOfSync { expected: next_id, got: e.first_update_id })
+        }
+
+        let time = TickTime::new(e.event_time(), Some(e.final_update_id));
+        self.depths.extend(
+            e.bids.to_levels(orderbook::Side::Bid, e.bids.len()),
+            e.asks.to_levels(orderbook::Side::Ask, e.asks.len()),
+        );
+        self.last_update_id = e.final_update_id;
+        self.synced = true;
+
+        Ok(Some(self.depths.to_tick(Exchange::Binance, &self.symbol, MAX_DEPTH, time)))
+    }
+}
+
+/// Binance's name of an instrument, e.g. `ETHBTC` for `ETH/BTC`.
+fn venue_symbol(instrument: &Instrument) -> String {
+    format!(
+        "{}{}",
+        Instrument::venue_asset(&instrument.base, ALIASES),
+        Instrument::venue_asset(&instrument.quote, ALIASES),
+    )
+}
 
-        Some(InTick { exchange: Exchange::Binance, bids, asks })
+/// How Binance lists a symbol, none if it doesn't.
+async fn listing(client: &reqwest::Client, rest_url: &str, venue_symbol: &str) -> Result<Option<SymbolInfo>, Error> {
+    let url = format!("{}/exchangeInfo?symbol={}", rest_url, venue_symbol);
+    let res = client.get(url).send().await?;
+
+    // an unknown symbol is a bad request
+    if res.status() == reqwest::StatusCode::BAD_REQUEST {
+        return Ok(None)
     }
+    let info: ExchangeInfo = res.error_for_status()?.json().await?;
+    Ok(info.symbols.into_iter().next())
 }
 
-pub(crate) async fn connect(symbol: &String) -> Result<websocket::WsStream, Error> {
-    let depth = DEPTH;
-    let symbol = symbol.to_lowercase().replace("/", "");
-    let url = format!("{}/{}@depth{}@100ms", BINANCE_WS_URL, symbol, depth);
-    Ok(websocket::connect(url.as_str()).await?)
+/// Connects to a combined stream carrying the order book, or the best bid and
+/// ask, of every symbol.
+pub(crate) async fn connect(
+    url: &str,
+    venue_symbols: &[&String],
+    stream: BookStream,
+    bbo: bool,
+) -> Result<websocket::WsStream, Error>
+{
+    let streams: Vec<String> = venue_symbols.iter()
+        .map(|symbol| match (bbo, stream) {
+            (true, _) => format!("{}@bookTicker", symbol),
+            (false, BookStream::Partial) => format!("{}@depth{}@100ms", symbol, PARTIAL_DEPTH),
+            (false, BookStream::Diff) => format!("{}@depth@100ms", symbol),
+        })
+        .collect();
+    let url = format!("{}/stream?streams={}", url, streams.join("/"));
+    websocket::connect(url.as_str()).await
 }
 
-pub(crate) fn parse(msg: Message) -> Result<Option<InTick>, Error> {
+/// Fetches the order book snapshot the diff stream is synchronized against.
+async fn snapshot(rest_url: &str, symbol: &str) -> Result<Event, Error> {
+    let url = format!("{}/depth?symbol={}&limit={}", rest_url, symbol, SNAPSHOT_LIMIT);
+
+    let snapshot: Event = reqwest::get(url).await?
+        .error_for_status()?
+        .json().await?;
+    info!("Binance snapshot of {} at update {}", symbol, snapshot.last_update_id);
+
+    Ok(snapshot)
+}
+
+pub(crate) fn parse(
+    msg: Message,
+    symbols: &HashMap<String, VenueInstrument>,
+) -> Result<Option<InTick>, Error>
+{
     let e = match msg {
         Message::Text(x) => {
-            let e= deserialize(x)?;
+            let e: Combined<Event> = serde_json::from_str(&x)
+                .inspect_err(|_| metrics::parse_error(Exchange::Binance))?;
             debug!("{:?}", e);
             Some(e)
         },
@@ -54,9 +404,119 @@ pub(crate) fn parse(msg: Message) -> Result<Option<InTick>, Error> {
             None
         }
     };
-    Ok(e.map(|e| e.maybe_to_tick()).flatten())
+    Ok(e.and_then(|e| {
+        symbols.get(e.venue_symbol())
+            .and_then(|instrument| e.data.maybe_to_tick(&instrument.symbol()))
+    }))
+}
+
+fn parse_ticker(
+    msg: Message,
+    symbols: &HashMap<String, VenueInstrument>,
+) -> Result<Option<InTick>, Error>
+{
+    match msg {
+        Message::Text(x) => {
+            let e: Combined<BookTicker> = serde_json::from_str(&x)
+                .inspect_err(|_| metrics::parse_error(Exchange::Binance))?;
+            debug!("{:?}", e);
+            Ok(symbols.get(e.venue_symbol())
+                .and_then(|instrument| e.data.maybe_to_tick(&instrument.symbol())))
+        },
+        _ => Ok(None),
+    }
+}
+
+fn parse_diff(
+    msg: Message,
+    books: &mut HashMap<String, LocalBook>,
+) -> Result<Option<InTick>, Error>
+{
+    match msg {
+        Message::Text(x) => {
+            let e: Combined<DiffEvent> = serde_json::from_str(&x)
+                .inspect_err(|_| metrics::parse_error(Exchange::Binance))?;
+            debug!("{:?}", e);
+            match books.get_mut(e.venue_symbol()) {
+                Some(book) => book.apply(e.data),
+                None => Ok(None),
+            }
+        },
+        _ => Ok(None),
+    }
 }
 
-fn deserialize(s: String) -> serde_json::Result<Event> {
-    Ok(serde_json::from_str(&s)?)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn level(price: Decimal, amount: Decimal) -> Level {
+        Level { price, amount }
+    }
+
+    fn book() -> LocalBook {
+        let snapshot = Event {
+            last_update_id: 100,
+            bids: vec![level(dec!(0.069), dec!(1)), level(dec!(0.068), dec!(2))],
+            asks: vec![level(dec!(0.070), dec!(1)), level(dec!(0.071), dec!(2))],
+        };
+        LocalBook::new("ETH/BTC", snapshot)
+    }
+
+    fn diff(first_update_id: u64, final_update_id: u64, bids: Vec<Level>, asks: Vec<Level>) -> DiffEvent {
+        DiffEvent { event_time: 1_650_000_000_000, first_update_id, final_update_id, bids, asks }
+    }
+
+    #[test]
+    fn drops_updates_older_than_the_snapshot() {
+        let mut book = book();
+        let tick = book.apply(diff(90, 100, vec![level(dec!(0.069), dec!(0))], vec![])).unwrap();
+
+        assert_eq!(tick, None);
+        assert_eq!(book.last_update_id, 100);
+        assert!(!book.synced);
+    }
+
+    #[test]
+    fn first_update_must_straddle_the_snapshot() {
+        let mut book = book();
+        assert!(matches!(
+            book.apply(diff(102, 105, vec![], vec![])),
+            Err(Error::OutOfSync { expected: 101, got: 102 })
+        ));
+
+        let tick = book.apply(diff(95, 105, vec![level(dec!(0.069), dec!(3))], vec![])).unwrap().unwrap();
+        assert_eq!(tick.bids[0].amount, dec!(3));
+        assert_eq!(tick.time.sequence, Some(105));
+        assert!(book.synced);
+    }
+
+    #[test]
+    fn following_updates_must_pick_up_where_the_last_ended() {
+        let mut book = book();
+        book.apply(diff(101, 105, vec![], vec![])).unwrap();
+        book.apply(diff(106, 110, vec![], vec![level(dec!(0.070), dec!(0))])).unwrap();
+        assert_eq!(book.last_update_id, 110);
+
+        // an update which straddles the last one is a gap once synced
+        assert!(matches!(
+            book.apply(diff(109, 112, vec![], vec![])),
+            Err(Error::OutOfSync { expected: 111, got: 109 })
+        ));
+        assert!(matches!(
+            book.apply(diff(112, 115, vec![], vec![])),
+            Err(Error::OutOfSync { expected: 111, got: 112 })
+        ));
+    }
+
+    #[test]
+    fn zero_amounts_remove_levels() {
+        let mut book = book();
+        let tick = book.apply(diff(101, 101, vec![level(dec!(0.069), dec!(0))], vec![level(dec!(0.070), dec!(0))]))
+            .unwrap().unwrap();
+
+        assert_eq!(tick.bids.iter().map(|l| l.price).collect::<Vec<_>>(), vec![dec!(0.068)]);
+        assert_eq!(tick.asks.iter().map(|l| l.price).collect::<Vec<_>>(), vec![dec!(0.071)]);
+    }
 }
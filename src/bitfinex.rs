@@ -0,0 +1,425 @@
+use crate::adapter::ExchangeAdapter;
+use crate::error::Error;
+use crate::instrument::{self, Aliases, Instrument, VenueInstrument};
+use crate::metrics;
+use crate::orderbook::{self, Exchange, InTick, OrderDepthsMap, TickTime};
+use crate::{MAX_DEPTH, websocket};
+use futures::SinkExt;
+use log::{debug, info, warn};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tungstenite::Message;
+
+/// Levels per side of the book subscribed to, Bitfinex offers 1, 25, 100 and 250.
+const BOOK_DEPTH: usize = 100;
+
+/// Depth of the book streaming just the best bid and ask.
+const BBO_DEPTH: usize = 1;
+
+/// Bitfinex sends a heartbeat every 15 seconds on a channel without updates.
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Bitfinex asks to reconnect with this info code, e.g. ahead of maintenance.
+const RECONNECT_CODE: u32 = 20051;
+
+/// Assets Bitfinex names differently.
+const ALIASES: Aliases = &[("USDT", "UST"), ("DASH", "DSH"), ("QTUM", "QTM"), ("IOTA", "IOT")];
+
+/// Streams the `book` channel of every symbol over one connection. Messages
+/// are arrays led by the numeric id of their channel, which `subscribed` maps
+/// to the symbol.
+pub(crate) struct Adapter {
+    /// Instruments keyed by their Bitfinex symbol, e.g. `tETHBTC` -> `ETH/BTC`.
+    symbols: HashMap<String, VenueInstrument>,
+
+    /// Symbols keyed by the id of their channel on the current connection.
+    channels: HashMap<u64, String>,
+
+    /// Symbols subscribed to but not answered yet.
+    pending: Vec<String>,
+
+    books: HashMap<u64, OrderDepthsMap>,
+
+    /// When each channel last sent anything, heartbeats included.
+    last_seen: HashMap<u64, Instant>,
+
+    /// Set by an info event asking to reconnect.
+    reconnect_requested: bool,
+
+    /// Levels per side subscribed to, `BBO_DEPTH` for the best bid and ask.
+    depth: usize,
+
+    precision: Precision,
+
+    url: String,
+
+    /// REST endpoint, e.g. `BITFINEX_REST_URL`.
+    rest_url: String,
+}
+
+impl Adapter {
+    pub(crate) fn new(instruments: &[Instrument], bbo: bool, precision: Precision, url: &str, rest_url: &str) -> Adapter {
+        let symbols = instruments.iter()
+            .map(|i| VenueInstrument::new(i, venue_symbol(i)))
+            .map(|i| (i.venue_symbol.clone(), i))
+            .collect();
+        let depth = match bbo {
+            true => BBO_DEPTH,
+            false => BOOK_DEPTH,
+        };
+        Adapter {
+            symbols,
+            channels: HashMap::new(),
+            pending: vec![],
+            books: HashMap::new(),
+            last_seen: HashMap::new(),
+            reconnect_requested: false,
+            depth,
+            precision,
+            url: url.to_string(),
+            rest_url: rest_url.to_string(),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl ExchangeAdapter for Adapter {
+    fn exchange(&self) -> Exchange {
+        Exchange::Bitfinex
+    }
+
+    /// Bitfinex lists the names of its pairs, prices go by significant digits
+    /// rather than a tick size.
+    async fn resolve(&mut self) -> Result<(), Error> {
+        let pairs = match pairs(&self.rest_url).await {
+            Ok(pairs) => pairs,
+            Err(e) => {
+                warn!("Failed to look up the pairs of Bitfinex, streaming them unchecked: {:?}", e);
+                return Ok(())
+            },
+        };
+
+        for instrument in self.symbols.values() {
+            let pair = instrument.venue_symbol.trim_start_matches('t');
+            if !pairs.iter().any(|p| p == pair) {
+                return Err(Error::NotListed {
+                    exchange: Exchange::Bitfinex,
+                    instrument: instrument.instrument.clone(),
+                })
+            }
+            info!("Bitfinex lists {}", instrument);
+        }
+        Ok(())
+    }
+
+    async fn connect(&mut self) -> Result<websocket::WsStream, Error> {
+        websocket::connect(self.url.as_str()).await
+    }
+
+    /// Subscribes to the book of every symbol on its own channel.
+    async fn subscribe(&mut self, ws_stream: &mut websocket::WsStream) -> Result<(), Error> {
+        self.channels.clear();
+        self.books.clear();
+        self.last_seen.clear();
+        self.reconnect_requested = false;
+        self.pending = self.symbols.keys().cloned().collect();
+
+        for symbol in &self.pending {
+            let msg = serde_json::to_string(&Event::Subscribe {
+                channel: "book".to_string(),
+                symbol: symbol.clone(),
+                prec: self.precision,
+                len: self.depth.to_string(),
+            })?;
+            ws_stream.send(Message::Text(msg)).await?;
+        }
+        Ok(())
+    }
+
+    /// Bitfinex answers every subscription with `subscribed` and the id of
+    /// the channel.
+    fn unconfirmed(&self) -> Vec<Instrument> {
+        self.pending.iter()
+            .filter_map(|symbol| self.symbols.get(symbol))
+            .map(|i| i.instrument.clone())
+            .collect()
+    }
+
+    fn parse(&mut self, msg: Message) -> Result<Option<InTick>, Error> {
+        let text = match msg {
+            Message::Text(text) => text,
+            _ => return Ok(None),
+        };
+        let incoming: Incoming = serde_json::from_str(&text)
+            .inspect_err(|_| metrics::parse_error(Exchange::Bitfinex))?;
+
+        let tick = match incoming {
+            Incoming::Channel(chan_id, data) => {
+                self.last_seen.insert(chan_id, Instant::now());
+                match data {
+                    ChannelData::Heartbeat(hb) => {
+                        debug!("{} on channel {}", hb, chan_id);
+                        None
+                    },
+                    ChannelData::Snapshot(entries) => {
+                        let mut book = OrderDepthsMap::with_depth(self.depth);
+                        let (bids, asks) = levels(&entries);
+                        book.reset(bids, asks);
+                        self.books.insert(chan_id, book);
+                        self.to_tick(chan_id)
+                    },
+                    ChannelData::Update(entry) => match self.books.get_mut(&chan_id) {
+                        Some(book) => {
+                            let (bids, asks) = levels(&[entry]);
+                            book.extend(bids, asks);
+                            self.to_tick(chan_id)
+                        },
+                        None => None,
+                    },
+                }
+            },
+            Incoming::Event(Event::Subscribed { chan_id, symbol }) => {
+                info!("Subscribed to {} on channel {}", symbol, chan_id);
+                self.pending.retain(|s| *s != symbol);
+                self.last_seen.insert(chan_id, Instant::now());
+                self.channels.insert(chan_id, symbol);
+                None
+            },
+            Incoming::Event(Event::Error { msg, code, symbol }) => {
+                return Err(Error::Rejected {
+                    exchange: Exchange::Bitfinex,
+                    instrument: symbol.and_then(|s| self.symbols.get(&s)).map(|i| i.instrument.clone()),
+                    code: code.map(|c| c.to_string()),
+                    message: msg,
+                })
+            },
+            Incoming::Event(Event::Info { code, msg }) => {
+                if code == Some(RECONNECT_CODE) {
+                    info!("Bitfinex asked to reconnect: {:?}", msg);
+                    self.reconnect_requested = true;
+                }
+                None
+            },
+            Incoming::Event(_) => None,
+        };
+
+        // a channel falling silent is dead even while the others stream
+        if let Some((chan_id, _)) = self.last_seen.iter().find(|(_, at)| at.elapsed() > HEARTBEAT_TIMEOUT) {
+            warn!("No Bitfinex heartbeat on channel {} for {:?}", chan_id, HEARTBEAT_TIMEOUT);
+            return Err(Error::Silent(HEARTBEAT_TIMEOUT))
+        }
+        Ok(tick)
+    }
+
+    fn reconnect_due(&self) -> bool {
+        self.reconnect_requested
+    }
+
+    fn idle_timeout(&self) -> Option<Duration> {
+        Some(HEARTBEAT_TIMEOUT)
+    }
+}
+
+impl Adapter {
+    fn to_tick(&self, chan_id: u64) -> Option<InTick> {
+        let instrument = self.channels.get(&chan_id).and_then(|s| self.symbols.get(s))?;
+        let book = self.books.get(&chan_id)?;
+        Some(book.to_tick(Exchange::Bitfinex, &instrument.symbol(), MAX_DEPTH, TickTime::new(None, None)))
+    }
+}
+
+/// Precision of the book, from `P0` with five significant digits down to `P4`
+/// with one, aggregating the levels to match.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ArgEnum, Deserialize, Serialize)]
+pub enum Precision {
+    #[default]
+    #[clap(name = "P0")]
+    P0,
+
+    #[clap(name = "P1")]
+    P1,
+
+    #[clap(name = "P2")]
+    P2,
+
+    #[clap(name = "P3")]
+    P3,
+
+    #[clap(name = "P4")]
+    P4,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Incoming {
+    /// `[CHANNEL_ID, ...]`
+    Channel(u64, ChannelData),
+
+    Event(Event),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ChannelData {
+    /// Always `hb`.
+    Heartbeat(String),
+
+    Snapshot(Vec<Entry>),
+
+    Update(Entry),
+}
+
+/// `[PRICE, COUNT, AMOUNT]`, a positive amount for a bid and a negative one for
+/// an ask. A count of 0 deletes the level, with an amount of 1 for a bid and -1
+/// for an ask.
+#[derive(Debug, Clone, Deserialize)]
+struct Entry(Decimal, u64, Decimal);
+
+/// Splits entries into bid and ask levels, deleted ones with a zero amount.
+fn levels(entries: &[Entry]) -> (Vec<orderbook::Level>, Vec<orderbook::Level>) {
+    let mut bids = vec![];
+    let mut asks = vec![];
+    for Entry(price, count, amount) in entries {
+        let size = match count {
+            0 => Decimal::ZERO,
+            _ => amount.abs(),
+        };
+        match amount.is_sign_positive() {
+            true => bids.push(orderbook::Level::new(orderbook::Side::Bid, *price, size, Exchange::Bitfinex)),
+            false => asks.push(orderbook::Level::new(orderbook::Side::Ask, *price, size, Exchange::Bitfinex)),
+        }
+    }
+    (bids, asks)
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum Event {
+    Info { code: Option<u32>, msg: Option<String> },
+
+    Subscribe { channel: String, symbol: String, prec: Precision, len: String },
+
+    Subscribed {
+        #[serde(rename = "chanId")]
+        chan_id: u64,
+        symbol: String,
+    },
+
+    Error { msg: String, code: Option<u32>, symbol: Option<String> },
+
+    #[serde(other)]
+    Other,
+}
+
+/// Bitfinex's name of an instrument, e.g. `tETHBTC` for `ETH/BTC`, with a colon
+/// between assets longer than three letters, e.g. `tDOGE:USD`.
+fn venue_symbol(instrument: &Instrument) -> String {
+    let base = Instrument::venue_asset(&instrument.base, ALIASES);
+    let quote = Instrument::venue_asset(&instrument.quote, ALIASES);
+    match base.len() > 3 || quote.len() > 3 {
+        true => format!("t{}:{}", base, quote),
+        false => format!("t{}{}", base, quote),
+    }
+}
+
+/// Every pair Bitfinex trades, e.g. `ETHBTC`.
+async fn pairs(rest_url: &str) -> Result<Vec<String>, Error> {
+    let url = format!("{}/conf/pub:list:pair:exchange", rest_url);
+    let lists: Vec<Vec<String>> = instrument::listing_client()?
+        .get(url).send().await?
+        .error_for_status()?
+        .json().await?;
+    debug!("{:?}", lists);
+    Ok(lists.into_iter().flatten().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn text(s: &str) -> Message {
+        Message::Text(s.to_string())
+    }
+
+    /// An adapter streaming `tETHBTC` on channel 17.
+    fn adapter() -> Adapter {
+        let mut adapter = Adapter::new(&["ETH/BTC".parse().unwrap()], false, Precision::P0, "ws://127.0.0.1:1", "http://127.0.0.1:1");
+        let subscribed = r#"{"event": "subscribed", "channel": "book", "chanId": 17, "symbol": "tETHBTC", "prec": "P0", "len": "100"}"#;
+        assert!(adapter.parse(text(subscribed)).unwrap().is_none());
+        adapter
+    }
+
+    const SNAPSHOT: &str = "[17, [[0.069, 1, 1.5], [0.068, 2, 2.5], [0.070, 1, -1.5], [0.071, 3, -2.5]]]";
+
+    fn prices(levels: &[orderbook::Level]) -> Vec<Decimal> {
+        levels.iter().map(|l| l.price).collect()
+    }
+
+    #[test]
+    fn heartbeat_carries_no_tick() {
+        let mut adapter = adapter();
+        assert!(matches!(serde_json::from_str(r#"[17, "hb"]"#), Ok(Incoming::Channel(17, ChannelData::Heartbeat(_)))));
+        assert!(adapter.parse(text(r#"[17, "hb"]"#)).unwrap().is_none());
+    }
+
+    #[test]
+    fn snapshot_splits_the_sides_by_the_sign_of_the_amount() {
+        let mut adapter = adapter();
+        assert!(matches!(serde_json::from_str(SNAPSHOT), Ok(Incoming::Channel(17, ChannelData::Snapshot(_)))));
+
+        let tick = adapter.parse(text(SNAPSHOT)).unwrap().unwrap();
+        assert_eq!(tick.symbol, "ETH/BTC");
+        assert_eq!(prices(&tick.bids), vec![dec!(0.069), dec!(0.068)]);
+        assert_eq!(prices(&tick.asks), vec![dec!(0.070), dec!(0.071)]);
+        assert_eq!(tick.asks[0].amount, dec!(1.5));
+    }
+
+    #[test]
+    fn update_changes_a_single_level() {
+        let mut adapter = adapter();
+        adapter.parse(text(SNAPSHOT)).unwrap();
+        assert!(matches!(serde_json::from_str("[17, [0.0695, 2, 4]]"), Ok(Incoming::Channel(17, ChannelData::Update(_)))));
+
+        let tick = adapter.parse(text("[17, [0.0695, 2, 4]]")).unwrap().unwrap();
+        assert_eq!(prices(&tick.bids), vec![dec!(0.0695), dec!(0.069), dec!(0.068)]);
+        assert_eq!(tick.bids[0].amount, dec!(4));
+        assert_eq!(prices(&tick.asks), vec![dec!(0.070), dec!(0.071)]);
+    }
+
+    #[test]
+    fn count_of_zero_deletes_the_level_on_its_side() {
+        let mut adapter = adapter();
+        adapter.parse(text(SNAPSHOT)).unwrap();
+
+        let tick = adapter.parse(text("[17, [0.069, 0, 1]]")).unwrap().unwrap();
+        assert_eq!(prices(&tick.bids), vec![dec!(0.068)]);
+        assert_eq!(prices(&tick.asks), vec![dec!(0.070), dec!(0.071)]);
+
+        let tick = adapter.parse(text("[17, [0.071, 0, -1]]")).unwrap().unwrap();
+        assert_eq!(prices(&tick.bids), vec![dec!(0.068)]);
+        assert_eq!(prices(&tick.asks), vec![dec!(0.070)]);
+    }
+
+    #[test]
+    fn update_before_the_snapshot_carries_no_tick() {
+        let mut adapter = adapter();
+        assert!(adapter.parse(text("[17, [0.0695, 2, 4]]")).unwrap().is_none());
+    }
+
+    #[test]
+    fn subscription_asks_for_the_precision() {
+        let subscribe = Event::Subscribe {
+            channel: "book".to_string(),
+            symbol: "tETHBTC".to_string(),
+            prec: Precision::P2,
+            len: "100".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_string(&subscribe).unwrap(),
+            r#"{"event":"subscribe","channel":"book","symbol":"tETHBTC","prec":"P2","len":"100"}"#,
+        );
+    }
+}
@@ -1,13 +1,164 @@
 use chrono::{DateTime, Utc};
+use crate::adapter::ExchangeAdapter;
 use crate::error::Error;
-use crate::orderbook::{self, Exchange, InTick, ToLevel, ToLevels, ToTick};
-use crate::{BITSTAMP_WS_URL, DEPTH, websocket};
+use crate::instrument::{self, Aliases, Instrument, VenueInstrument};
+use crate::metrics;
+use crate::orderbook::{self, Exchange, InTick, OrderDepthsMap, TickTime, ToLevel, ToLevels, ToTick};
+use crate::{BookStream, MAX_DEPTH, websocket};
 use futures::SinkExt;
-use log::{debug, info};
+use log::{debug, info, warn};
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
 use tungstenite::protocol::Message;
 
+/// Assets Bitstamp names differently, none so far.
+const ALIASES: Aliases = &[];
+
+pub(crate) struct Adapter {
+    /// Instruments keyed by the channel streaming their order book.
+    channels: HashMap<Channel, VenueInstrument>,
+
+    /// Channels subscribed to but not answered yet, in the order subscribed.
+    pending: VecDeque<Channel>,
+
+    /// Set by `bts:request_reconnect`, sent ahead of maintenance.
+    reconnect_requested: bool,
+
+    stream: BookStream,
+    books: HashMap<Channel, LocalBook>,
+    url: String,
+
+    /// REST endpoint, e.g. `BITSTAMP_REST_URL`.
+    rest_url: String,
+}
+
+impl Adapter {
+    pub(crate) fn new(instruments: &[Instrument], stream: BookStream, url: &str, rest_url: &str) -> Adapter {
+        let channels = instruments.iter()
+            .map(|i| VenueInstrument::new(i, venue_symbol(i)))
+            .map(|i| (channel(&i.venue_symbol, stream), i))
+            .collect();
+        Adapter {
+            channels,
+            pending: VecDeque::new(),
+            reconnect_requested: false,
+            stream,
+            books: HashMap::new(),
+            url: url.to_string(),
+            rest_url: rest_url.to_string(),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl ExchangeAdapter for Adapter {
+    fn exchange(&self) -> Exchange {
+        Exchange::Bitstamp
+    }
+
+    /// Bitstamp lists every pair at once, along with the decimals of its prices
+    /// and amounts.
+    async fn resolve(&mut self) -> Result<(), Error> {
+        let pairs = match pairs(&self.rest_url).await {
+            Ok(pairs) => pairs,
+            Err(e) => {
+                warn!("Failed to look up the pairs of Bitstamp, streaming them unchecked: {:?}", e);
+                return Ok(())
+            },
+        };
+
+        let mut channels = HashMap::new();
+        for (_, mut instrument) in self.channels.drain() {
+            let name = format!(
+                "{}/{}",
+                Instrument::venue_asset(&instrument.instrument.base, ALIASES),
+                Instrument::venue_asset(&instrument.instrument.quote, ALIASES),
+            );
+            let pair = pairs.iter().find(|p| p.name == name && p.trading == "Enabled")
+                .ok_or_else(|| Error::NotListed {
+                    exchange: Exchange::Bitstamp,
+                    instrument: instrument.instrument.clone(),
+                })?;
+
+            instrument.venue_symbol = pair.url_symbol.clone();
+            instrument.tick_size = Some(Decimal::new(1, pair.counter_decimals));
+            instrument.lot_size = Some(Decimal::new(1, pair.base_decimals));
+            info!("Bitstamp lists {}", instrument);
+
+            channels.insert(channel(&instrument.venue_symbol, self.stream), instrument);
+        }
+        self.channels = channels;
+        Ok(())
+    }
+
+    async fn connect(&mut self) -> Result<websocket::WsStream, Error> {
+        connect(&self.url).await
+    }
+
+    /// Subscribes to the channel of every symbol on the same connection. The diff
+    /// channels additionally need a snapshot to apply the buffered updates on.
+    async fn subscribe(&mut self, ws_stream: &mut websocket::WsStream) -> Result<(), Error> {
+        self.pending.clear();
+        self.reconnect_requested = false;
+        for channel in self.channels.keys() {
+            subscribe(ws_stream, channel).await?;
+            self.pending.push_back(channel.clone());
+        }
+
+        self.books.clear();
+        if self.stream == BookStream::Diff {
+            for (channel, instrument) in &self.channels {
+                let book = LocalBook::new(&instrument.symbol(), snapshot(&self.rest_url, &instrument.venue_symbol).await?);
+                self.books.insert(channel.clone(), book);
+            }
+        }
+        Ok(())
+    }
+
+    /// Bitstamp answers every subscription with `bts:subscription_succeeded`.
+    fn unconfirmed(&self) -> Vec<Instrument> {
+        self.pending.iter()
+            .filter_map(|channel| self.channels.get(channel))
+            .map(|i| i.instrument.clone())
+            .collect()
+    }
+
+    fn parse(&mut self, msg: Message) -> Result<Option<InTick>, Error> {
+        match read(msg)? {
+            Some(Event::SubscriptionSucceeded { channel, .. }) => {
+                self.pending.retain(|c| *c != channel);
+                Ok(None)
+            },
+            Some(Event::Error { data, .. }) => Err(self.rejected(data)),
+            Some(Event::RequestReconnect { .. }) => {
+                self.reconnect_requested = true;
+                Ok(None)
+            },
+            Some(e) => match self.stream {
+                BookStream::Partial => Ok(to_tick(e, &self.channels)),
+                BookStream::Diff => apply_diff(e, &mut self.books),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn reconnect_due(&self) -> bool {
+        self.reconnect_requested
+    }
+}
+
+impl Adapter {
+    /// Bitstamp answers subscriptions in order and its errors don't name the
+    /// channel, so an error is put down to the oldest subscription unanswered.
+    fn rejected(&mut self, e: InError) -> Error {
+        let instrument = self.pending.pop_front()
+            .and_then(|channel| self.channels.get(&channel))
+            .map(|i| i.instrument.clone());
+        Error::Rejected { exchange: Exchange::Bitstamp, instrument, code: e.code, message: e.message }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 #[serde(tag = "event")]
 enum Event {
@@ -28,22 +179,43 @@ enum Event {
 
     #[serde(rename = "bts:error")]
     Error{data: InError, channel: Channel},
+
+    /// Bitstamp is about to close the connection, e.g. for maintenance.
+    #[serde(rename = "bts:request_reconnect")]
+    RequestReconnect{channel: Channel},
 }
 
 impl ToTick for Event {
-    fn maybe_to_tick(&self) -> Option<InTick> {
+    fn maybe_to_tick(&self, symbol: &str) -> Option<InTick> {
         match self {
             Event::Data { data, .. } => {
-                let bids = data.bids.to_levels(orderbook::Side::Bid, DEPTH);
-                let asks = data.asks.to_levels(orderbook::Side::Ask, DEPTH);
+                let bids = data.bids.to_levels(orderbook::Side::Bid, MAX_DEPTH);
+                let asks = data.asks.to_levels(orderbook::Side::Ask, MAX_DEPTH);
+                let time = TickTime::new(Some(data.microtimestamp), None);
 
-                Some(InTick { exchange: Exchange::Bitstamp, bids, asks })
+                Some(InTick { exchange: Exchange::Bitstamp, symbol: symbol.to_string(), bids, asks, time })
             },
             _ => None,
         }
     }
 }
 
+/// A pair as listed by `/trading-pairs-info/`.
+#[derive(Debug, Deserialize)]
+struct Pair {
+    /// E.g. `ETH/BTC`.
+    name: String,
+
+    /// E.g. `ethbtc`.
+    url_symbol: String,
+
+    base_decimals: u32,
+    counter_decimals: u32,
+
+    /// `Enabled` unless trading is halted.
+    trading: String,
+}
+
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 struct OutSubscription {
     channel: Channel,
@@ -84,19 +256,85 @@ impl ToLevel for Level {
 
 type Channel = String;
 
-pub(crate) async fn connect(symbol: &String) -> Result<websocket::WsStream, Error> {
-    let mut ws_stream = websocket::connect(BITSTAMP_WS_URL).await?;
-    subscribe(&mut ws_stream, symbol).await?;
-    Ok(ws_stream)
+/// The local book of the diff channel along with the time of the last update applied.
+#[derive(Debug)]
+struct LocalBook {
+    symbol: String,
+    microtimestamp: DateTime<Utc>,
+    synced: bool,
+    depths: OrderDepthsMap,
 }
 
-pub(crate) fn parse(msg: Message) -> Result<Option<InTick>, Error> {
+impl LocalBook {
+    fn new(symbol: &str, snapshot: InData) -> Self {
+        let mut depths = OrderDepthsMap::new();
+        depths.reset(
+            snapshot.bids.to_levels(orderbook::Side::Bid, snapshot.bids.len()),
+            snapshot.asks.to_levels(orderbook::Side::Ask, snapshot.asks.len()),
+        );
+        LocalBook {
+            symbol: symbol.to_string(),
+            microtimestamp: snapshot.microtimestamp,
+            synced: false,
+            depths,
+        }
+    }
+
+    /// Applies a diff update. Updates older than the snapshot are dropped, after
+    /// that every update must be strictly newer than the previous one.
+    fn apply(&mut self, data: InData) -> Result<Option<InTick>, Error> {
+        if data.microtimestamp <= self.microtimestamp {
+            return match self.synced {
+                false => Ok(None),
+                true => Err(Error::OutOfSync {
+                    expected: self.microtimestamp.timestamp_micros() as u64 + 1,
+                    got: data.microtimestamp.timestamp_micros() as u64,
+                }),
+            }
+        }
+
+        let time = TickTime::new(Some(data.microtimestamp), None);
+        self.depths.extend(
+            data.bids.to_levels(orderbook::Side::Bid, data.bids.len()),
+            data.asks.to_levels(orderbook::Side::Ask, data.asks.len()),
+        );
+        self.microtimestamp = data.microtimestamp;
+        self.synced = true;
+
+        Ok(Some(self.depths.to_tick(Exchange::Bitstamp, &self.symbol, MAX_DEPTH, time)))
+    }
+}
+
+pub(crate) async fn connect(url: &str) -> Result<websocket::WsStream, Error> {
+    websocket::connect(url).await
+}
+
+fn to_tick(e: Event, channels: &HashMap<Channel, VenueInstrument>) -> Option<InTick> {
+    match &e {
+        Event::Data { channel, .. } => channels.get(channel)
+            .and_then(|instrument| e.maybe_to_tick(&instrument.symbol())),
+        _ => None,
+    }
+}
+
+fn apply_diff(e: Event, books: &mut HashMap<Channel, LocalBook>) -> Result<Option<InTick>, Error> {
+    match e {
+        Event::Data { data, channel } => match books.get_mut(&channel) {
+            Some(book) => book.apply(data),
+            None => Ok(None),
+        },
+        _ => Ok(None),
+    }
+}
+
+fn read(msg: Message) -> Result<Option<Event>, Error> {
     let e = match msg {
 
         Message::Text(x) => {
             debug!("{:?}", x);
 
-            let e= deserialize(x)?;
+            let e= deserialize(x)
+                .inspect_err(|_| metrics::parse_error(Exchange::Bitstamp))?;
             match e {
                 Event::Data{..} => debug!("{:?}", e),
                 _ => info!("{:?}", e),
@@ -115,27 +353,65 @@ pub(crate) fn parse(msg: Message) -> Result<Option<InTick>, Error> {
 
          */
     };
-    Ok(e.map(|e| e.maybe_to_tick()).flatten())
+    Ok(e)
 }
 
-async fn subscribe (
+pub(crate) async fn subscribe (
     rx: &mut websocket::WsStream,
-    symbol: &String,
+    channel: &str,
 ) -> Result<(), Error>
 {
-    let symbol = symbol.to_lowercase().replace("/", "");
-    let channel = format!("order_book_{}", symbol);
+    let channel = channel.to_string();
     let msg = serialize(Event::Subscribe{ data: OutSubscription { channel } })?;
     rx.send(Message::Text(msg)).await?;
     Ok(())
 }
 
+/// Bitstamp's name of an instrument, e.g. `ethbtc` for `ETH/BTC`.
+fn venue_symbol(instrument: &Instrument) -> String {
+    format!(
+        "{}{}",
+        Instrument::venue_asset(&instrument.base, ALIASES),
+        Instrument::venue_asset(&instrument.quote, ALIASES),
+    ).to_lowercase()
+}
+
+/// The channel streaming the order book of a symbol, e.g. `order_book_ethbtc`.
+fn channel(symbol: &str, stream: BookStream) -> Channel {
+    match stream {
+        BookStream::Partial => format!("order_book_{}", symbol),
+        BookStream::Diff => format!("diff_order_book_{}", symbol),
+    }
+}
+
+/// Fetches the full order book snapshot the diff channel is synchronized against.
+async fn snapshot(rest_url: &str, symbol: &str) -> Result<InData, Error> {
+    let url = format!("{}/order_book/{}/", rest_url, symbol);
+
+    let snapshot: InData = reqwest::get(url).await?
+        .error_for_status()?
+        .json().await?;
+    info!("Bitstamp snapshot of {} at {}", symbol, snapshot.microtimestamp);
+
+    Ok(snapshot)
+}
+
+/// Every pair Bitstamp lists.
+async fn pairs(rest_url: &str) -> Result<Vec<Pair>, Error> {
+    let url = format!("{}/trading-pairs-info/", rest_url);
+    let pairs = instrument::listing_client()?
+        .get(url).send().await?
+        .error_for_status()?
+        .json().await?;
+    Ok(pairs)
+}
+
 fn deserialize(s: String) -> serde_json::Result<Event> {
-    Ok(serde_json::from_str(&s)?)
+    serde_json::from_str(&s)
 }
 
 fn serialize(e: Event) -> serde_json::Result<String> {
-    Ok(serde_json::to_string(&e)?)
+    serde_json::to_string(&e)
 }
 
 mod timestamp {
@@ -153,8 +429,10 @@ mod timestamp {
         where D: Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
-        let datetime = Utc.timestamp(i64::from_str(&s).map_err(serde::de::Error::custom)?, 0);
-        Ok(datetime)
+        let secs = i64::from_str(&s).map_err(serde::de::Error::custom)?;
+        Utc.timestamp_opt(secs, 0)
+            .single()
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid timestamp {}", secs)))
     }
 }
 
@@ -166,7 +444,7 @@ mod microtimestamp {
     pub fn serialize<S>(date: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error>
         where S: Serializer,
     {
-        serializer.serialize_i64(date.timestamp_nanos()/1000)
+        serializer.serialize_i64(date.timestamp_micros())
     }
 
     pub fn deserialize<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
@@ -178,6 +456,98 @@ mod microtimestamp {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use rust_decimal_macros::dec;
+
+    fn level(price: Decimal, amount: Decimal) -> Level {
+        Level { price, amount }
+    }
+
+    fn data(micros: i64, bids: Vec<Level>, asks: Vec<Level>) -> InData {
+        let microtimestamp = Utc.timestamp_nanos(micros * 1000);
+        InData { timestamp: microtimestamp, microtimestamp, bids, asks }
+    }
 
+    fn book() -> LocalBook {
+        let snapshot = data(
+            1_000,
+            vec![level(dec!(0.069), dec!(1)), level(dec!(0.068), dec!(2))],
+            vec![level(dec!(0.070), dec!(1)), level(dec!(0.071), dec!(2))],
+        );
+        LocalBook::new("ETH/BTC", snapshot)
+    }
 
+    #[test]
+    fn drops_updates_older_than_the_snapshot() {
+        let mut book = book();
+        let tick = book.apply(data(1_000, vec![level(dec!(0.069), dec!(0))], vec![])).unwrap();
 
+        assert_eq!(tick, None);
+        assert_eq!(book.microtimestamp.timestamp_micros(), 1_000);
+        assert!(!book.synced);
+    }
+
+    #[test]
+    fn following_updates_must_be_newer_than_the_last() {
+        let mut book = book();
+        let tick = book.apply(data(1_001, vec![level(dec!(0.069), dec!(3))], vec![])).unwrap().unwrap();
+        assert_eq!(tick.bids[0].amount, dec!(3));
+        assert!(book.synced);
+
+        book.apply(data(1_005, vec![], vec![])).unwrap();
+        assert_eq!(book.microtimestamp.timestamp_micros(), 1_005);
+
+        // an update no newer than the last one is out of sequence once synced
+        assert!(matches!(
+            book.apply(data(1_005, vec![], vec![])),
+            Err(Error::OutOfSync { expected: 1_006, got: 1_005 })
+        ));
+        assert!(matches!(
+            book.apply(data(1_002, vec![], vec![])),
+            Err(Error::OutOfSync { expected: 1_006, got: 1_002 })
+        ));
+    }
+
+    #[test]
+    fn zero_amounts_remove_levels() {
+        let mut book = book();
+        let tick = book.apply(data(1_001, vec![level(dec!(0.069), dec!(0))], vec![level(dec!(0.070), dec!(0))]))
+            .unwrap().unwrap();
+
+        assert_eq!(tick.bids.iter().map(|l| l.price).collect::<Vec<_>>(), vec![dec!(0.068)]);
+        assert_eq!(tick.asks.iter().map(|l| l.price).collect::<Vec<_>>(), vec![dec!(0.071)]);
+    }
+
+    #[test]
+    fn ticks_keep_the_best_levels() {
+        let mut book = book();
+        let bids = (1..=MAX_DEPTH as i64 + 50).map(|i| level(Decimal::new(i, 4), dec!(1))).collect();
+        let asks = (1..=MAX_DEPTH as i64 + 50).map(|i| level(Decimal::new(1_000 + i, 3), dec!(1))).collect();
+        let tick = book.apply(data(1_001, bids, asks)).unwrap().unwrap();
+
+        assert_eq!(tick.bids.len(), MAX_DEPTH);
+        assert_eq!(tick.asks.len(), MAX_DEPTH);
+        assert_eq!(tick.bids[0].price, dec!(0.069));
+        assert_eq!(tick.asks[0].price, dec!(0.070));
+        assert!(tick.bids.windows(2).all(|w| w[0].price > w[1].price));
+        assert!(tick.asks.windows(2).all(|w| w[0].price < w[1].price));
+    }
+
+    #[test]
+    fn updates_apply_to_the_book_of_their_channel() {
+        let mut books = HashMap::new();
+        books.insert("diff_order_book_ethbtc".to_string(), book());
+        let update = |channel: &str| Event::Data {
+            data: data(1_001, vec![], vec![level(dec!(0.0695), dec!(4))]),
+            channel: channel.to_string(),
+        };
+
+        assert_eq!(apply_diff(update("diff_order_book_ltcbtc"), &mut books).unwrap(), None);
+        let tick = apply_diff(update("diff_order_book_ethbtc"), &mut books).unwrap().unwrap();
+        assert_eq!(tick.symbol, "ETH/BTC");
+        assert_eq!(tick.asks[0].price, dec!(0.0695));
+    }
+}
@@ -0,0 +1,425 @@
+use chrono::{DateTime, Utc};
+use crate::adapter::ExchangeAdapter;
+use crate::error::Error;
+use crate::instrument::{self, Aliases, Instrument, VenueInstrument};
+use crate::metrics;
+use crate::orderbook::{self, Exchange, InTick, OrderDepthsMap, TickTime, ToLevel, ToLevels};
+use crate::{MAX_DEPTH, websocket};
+use futures::SinkExt;
+use log::{debug, info, warn};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tungstenite::Message;
+
+/// The level2 messages batched every 50ms, which unlike `level2` need no
+/// authentication.
+const BOOK_CHANNEL: &str = "level2_batch";
+
+/// Coinbase sends a heartbeat per product every second.
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Assets Coinbase names differently, none so far.
+const ALIASES: Aliases = &[];
+
+/// Streams the level2 channel, a snapshot followed by updates. Those carry no
+/// sequence numbers, so a gap in them can't be told, and the sequence of the
+/// heartbeats counts every event of a product rather than the book's. The
+/// heartbeat channel just tells whether a product is still streaming: one whose
+/// heartbeats stop or arrive out of order is resynchronized.
+pub(crate) struct Adapter {
+    /// Instruments keyed by their product id, e.g. `ETH-BTC` -> `ETH/BTC`.
+    products: HashMap<String, VenueInstrument>,
+
+    /// Products subscribed to but not confirmed yet.
+    pending: Vec<String>,
+
+    books: HashMap<String, OrderDepthsMap>,
+
+    /// The last heartbeat of every product, when it arrived and its sequence.
+    heartbeats: HashMap<String, (Instant, Option<u64>)>,
+
+    /// Set once a book may have missed updates, so it's subscribed to afresh.
+    resync: bool,
+
+    url: String,
+
+    /// REST endpoint, e.g. `COINBASE_REST_URL`.
+    rest_url: String,
+}
+
+impl Adapter {
+    pub(crate) fn new(instruments: &[Instrument], url: &str, rest_url: &str) -> Adapter {
+        let products = instruments.iter()
+            .map(|i| VenueInstrument::new(i, venue_symbol(i)))
+            .map(|i| (i.venue_symbol.clone(), i))
+            .collect();
+        Adapter {
+            products,
+            pending: vec![],
+            books: HashMap::new(),
+            heartbeats: HashMap::new(),
+            resync: false,
+            url: url.to_string(),
+            rest_url: rest_url.to_string(),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl ExchangeAdapter for Adapter {
+    fn exchange(&self) -> Exchange {
+        Exchange::Coinbase
+    }
+
+    async fn resolve(&mut self) -> Result<(), Error> {
+        let client = instrument::listing_client()?;
+        for instrument in self.products.values_mut() {
+            match listing(&client, &self.rest_url, &instrument.venue_symbol).await {
+                Ok(Some(product)) if !product.trading_disabled => {
+                    instrument.tick_size = Some(product.quote_increment.normalize());
+                    instrument.lot_size = Some(product.base_increment.normalize());
+                    info!("Coinbase lists {}", instrument);
+                },
+                Ok(_) => return Err(Error::NotListed {
+                    exchange: Exchange::Coinbase,
+                    instrument: instrument.instrument.clone(),
+                }),
+                Err(e) => warn!("Failed to look up {} on Coinbase, streaming {} unchecked: {:?}",
+                    instrument.symbol(), instrument.venue_symbol, e),
+            }
+        }
+        Ok(())
+    }
+
+    async fn connect(&mut self) -> Result<websocket::WsStream, Error> {
+        websocket::connect(self.url.as_str()).await
+    }
+
+    /// Subscribes to the book and heartbeats of every product at once.
+    async fn subscribe(&mut self, ws_stream: &mut websocket::WsStream) -> Result<(), Error> {
+        self.books.clear();
+        self.resync = false;
+        self.pending = self.products.keys().cloned().collect();
+        self.heartbeats = self.products.keys()
+            .map(|product| (product.clone(), (Instant::now(), None)))
+            .collect();
+
+        let msg = serde_json::to_string(&Event::Subscribe {
+            product_ids: self.pending.clone(),
+            channels: vec![BOOK_CHANNEL.to_string(), "heartbeat".to_string()],
+        })?;
+        ws_stream.send(Message::Text(msg)).await?;
+        Ok(())
+    }
+
+    /// Coinbase lists the products of every channel subscribed to in a
+    /// `subscriptions` message.
+    fn unconfirmed(&self) -> Vec<Instrument> {
+        self.pending.iter()
+            .filter_map(|product| self.products.get(product))
+            .map(|i| i.instrument.clone())
+            .collect()
+    }
+
+    fn parse(&mut self, msg: Message) -> Result<Option<InTick>, Error> {
+        let text = match msg {
+            Message::Text(text) => text,
+            _ => return Ok(None),
+        };
+        let e: Event = serde_json::from_str(&text)
+            .inspect_err(|_| metrics::parse_error(Exchange::Coinbase))?;
+
+        let tick = match e {
+            Event::Snapshot { product_id, bids, asks } => {
+                let mut book = OrderDepthsMap::new();
+                book.reset(
+                    bids.to_levels(orderbook::Side::Bid, bids.len()),
+                    asks.to_levels(orderbook::Side::Ask, asks.len()),
+                );
+                self.books.insert(product_id.clone(), book);
+                self.to_tick(&product_id, None)
+            },
+            Event::L2Update { product_id, time, changes } => {
+                match self.books.get_mut(&product_id) {
+                    Some(book) => {
+                        let (bids, asks): (Vec<Change>, Vec<Change>) = changes.into_iter()
+                            .partition(|c| c.side == ChangeSide::Buy);
+                        book.extend(
+                            bids.to_levels(orderbook::Side::Bid, bids.len()),
+                            asks.to_levels(orderbook::Side::Ask, asks.len()),
+                        );
+                        self.to_tick(&product_id, time)
+                    },
+                    None => None,
+                }
+            },
+            Event::Heartbeat { product_id, sequence } => {
+                self.heartbeat(product_id, sequence);
+                None
+            },
+            Event::Subscriptions { channels } => {
+                info!("Subscribed to {:?}", channels);
+                let subscribed: Vec<&String> = channels.iter()
+                    // the batched channel may be listed as `level2`
+                    .filter(|c| c.name.starts_with("level2"))
+                    .flat_map(|c| &c.product_ids)
+                    .collect();
+                self.pending.retain(|p| !subscribed.contains(&p));
+                None
+            },
+            Event::Error { message, reason } => {
+                let reason = reason.unwrap_or_default();
+                let instrument = self.pending.iter()
+                    .find(|p| reason.contains(p.as_str()))
+                    .and_then(|p| self.products.get(p))
+                    .map(|i| i.instrument.clone());
+                return Err(Error::Rejected {
+                    exchange: Exchange::Coinbase,
+                    instrument,
+                    code: None,
+                    message: format!("{}: {}", message, reason),
+                })
+            },
+            Event::Subscribe { .. } | Event::Other => None,
+        };
+
+        self.check_heartbeats();
+        Ok(tick)
+    }
+
+    fn reconnect_due(&self) -> bool {
+        self.resync
+    }
+
+    fn idle_timeout(&self) -> Option<Duration> {
+        Some(HEARTBEAT_TIMEOUT)
+    }
+}
+
+impl Adapter {
+    fn to_tick(&self, product_id: &str, time: Option<DateTime<Utc>>) -> Option<InTick> {
+        if self.resync {
+            return None
+        }
+        let instrument = self.products.get(product_id)?;
+        let book = self.books.get(product_id)?;
+        Some(book.to_tick(Exchange::Coinbase, &instrument.symbol(), MAX_DEPTH, TickTime::new(time, None)))
+    }
+
+    fn heartbeat(&mut self, product_id: String, sequence: u64) {
+        let last = self.heartbeats.insert(product_id.clone(), (Instant::now(), Some(sequence)));
+        if let Some((_, Some(last))) = last {
+            if sequence < last {
+                warn!("Coinbase heartbeat of {} went back from {} to {}, resyncing", product_id, last, sequence);
+                self.resync = true;
+            }
+        }
+    }
+
+    fn check_heartbeats(&mut self) {
+        if self.resync {
+            return
+        }
+        if let Some((product_id, _)) = self.heartbeats.iter().find(|(_, (at, _))| at.elapsed() > HEARTBEAT_TIMEOUT) {
+            warn!("No Coinbase heartbeat of {} for {:?}, resyncing", product_id, HEARTBEAT_TIMEOUT);
+            self.resync = true;
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Event {
+    Subscribe { product_ids: Vec<String>, channels: Vec<String> },
+
+    Subscriptions { channels: Vec<Channel> },
+
+    Snapshot { product_id: String, bids: Vec<Level>, asks: Vec<Level> },
+
+    L2Update {
+        product_id: String,
+
+        #[serde(with = "time", default)]
+        time: Option<DateTime<Utc>>,
+
+        changes: Vec<Change>,
+    },
+
+    Heartbeat { product_id: String, sequence: u64 },
+
+    Error { message: String, reason: Option<String> },
+
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Channel {
+    name: String,
+    product_ids: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+struct Level {
+    price: Decimal,
+    size: Decimal,
+}
+
+impl ToLevel for Level {
+    fn to_level(&self, side: orderbook::Side) -> orderbook::Level {
+        orderbook::Level::new(side, self.price, self.size, Exchange::Coinbase)
+    }
+}
+
+/// `[side, price, size]`, a size of zero removing the level.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+struct Change {
+    side: ChangeSide,
+    price: Decimal,
+    size: Decimal,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ChangeSide {
+    Buy,
+    Sell,
+}
+
+impl ToLevel for Change {
+    fn to_level(&self, side: orderbook::Side) -> orderbook::Level {
+        orderbook::Level::new(side, self.price, self.size, Exchange::Coinbase)
+    }
+}
+
+/// Coinbase's name of an instrument, e.g. `ETH-BTC` for `ETH/BTC`.
+fn venue_symbol(instrument: &Instrument) -> String {
+    format!(
+        "{}-{}",
+        Instrument::venue_asset(&instrument.base, ALIASES),
+        Instrument::venue_asset(&instrument.quote, ALIASES),
+    )
+}
+
+/// A product as listed by `/products/<product id>`.
+#[derive(Debug, Deserialize)]
+struct Product {
+    quote_increment: Decimal,
+    base_increment: Decimal,
+    trading_disabled: bool,
+}
+
+/// How Coinbase lists a product, none if it doesn't.
+async fn listing(client: &reqwest::Client, rest_url: &str, product_id: &str) -> Result<Option<Product>, Error> {
+    let url = format!("{}/products/{}", rest_url, product_id);
+    let res = client.get(url).send().await?;
+
+    if res.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None)
+    }
+    let product = res.error_for_status()?.json().await?;
+    debug!("{:?}", product);
+    Ok(Some(product))
+}
+
+mod time {
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S>(time: &Option<DateTime<Utc>>, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        time.map(|t| t.to_rfc3339()).serialize(serializer)
+    }
+
+    /// E.g. `2019-08-14T20:42:27.265Z`.
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error>
+        where D: Deserializer<'de>
+    {
+        let s = Option::<String>::deserialize(deserializer)?;
+        s.map(|s| DateTime::parse_from_rfc3339(&s)
+            .map(|t| t.with_timezone(&Utc))
+            .map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn adapter() -> Adapter {
+        Adapter::new(&["ETH/BTC".parse().unwrap()], "ws://127.0.0.1:1", "http://127.0.0.1:1")
+    }
+
+    fn text(s: &str) -> Message {
+        Message::Text(s.to_string())
+    }
+
+    const SNAPSHOT: &str = r#"{"type": "snapshot", "product_id": "ETH-BTC",
+        "bids": [["0.069", "1"], ["0.068", "2"]], "asks": [["0.070", "1"], ["0.071", "2"]]}"#;
+
+    const L2UPDATE: &str = r#"{"type": "l2update", "product_id": "ETH-BTC", "time": "2022-05-01T12:00:00.123456Z",
+        "changes": [["buy", "0.069", "0"], ["buy", "0.0685", "3"], ["sell", "0.070", "4"]]}"#;
+
+    fn heartbeat(sequence: u64) -> Message {
+        text(&format!(r#"{{"type": "heartbeat", "product_id": "ETH-BTC", "sequence": {}, "last_trade_id": 0}}"#, sequence))
+    }
+
+    #[test]
+    fn updates_apply_to_the_snapshot() {
+        let mut adapter = adapter();
+        adapter.parse(text(SNAPSHOT)).unwrap().unwrap();
+
+        let tick = adapter.parse(text(L2UPDATE)).unwrap().unwrap();
+        assert_eq!(tick.bids.iter().map(|l| (l.price, l.amount)).collect::<Vec<_>>(), vec![(dec!(0.0685), dec!(3)), (dec!(0.068), dec!(2))]);
+        assert_eq!(tick.asks.iter().map(|l| (l.price, l.amount)).collect::<Vec<_>>(), vec![(dec!(0.070), dec!(4)), (dec!(0.071), dec!(2))]);
+        assert!(tick.time.exchange.is_some());
+    }
+
+    #[test]
+    fn updates_before_the_snapshot_are_dropped() {
+        let mut adapter = adapter();
+        assert!(adapter.parse(text(L2UPDATE)).unwrap().is_none());
+    }
+
+    #[test]
+    fn heartbeats_in_order_keep_the_book() {
+        let mut adapter = adapter();
+        adapter.parse(text(SNAPSHOT)).unwrap();
+        adapter.parse(heartbeat(10)).unwrap();
+        adapter.parse(heartbeat(25)).unwrap();
+
+        assert!(!adapter.reconnect_due());
+        assert!(adapter.parse(text(L2UPDATE)).unwrap().is_some());
+    }
+
+    #[test]
+    fn heartbeat_out_of_order_resyncs() {
+        let mut adapter = adapter();
+        adapter.parse(text(SNAPSHOT)).unwrap();
+        adapter.parse(heartbeat(10)).unwrap();
+        adapter.parse(heartbeat(9)).unwrap();
+
+        assert!(adapter.reconnect_due());
+        assert!(adapter.parse(text(L2UPDATE)).unwrap().is_none());
+    }
+
+    #[test]
+    fn missing_heartbeat_resyncs() {
+        let mut adapter = adapter();
+        adapter.parse(text(SNAPSHOT)).unwrap();
+        adapter.parse(heartbeat(10)).unwrap();
+
+        // the last heartbeat is older than the timeout by the next message
+        let last = Instant::now().checked_sub(HEARTBEAT_TIMEOUT + Duration::from_secs(1)).unwrap();
+        adapter.heartbeats.insert("ETH-BTC".to_string(), (last, Some(10)));
+        adapter.parse(text(L2UPDATE)).unwrap();
+
+        assert!(adapter.reconnect_due());
+        assert!(adapter.parse(text(L2UPDATE)).unwrap().is_none());
+    }
+}
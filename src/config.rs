@@ -0,0 +1,337 @@
+use crate::bitfinex::Precision;
+use crate::error::Error;
+use crate::generic::JsonFormat;
+use crate::instrument::Instrument;
+use crate::orderbook::Exchange;
+use crate::ordermaster::{ExchangeSettings, Settings};
+use crate::{BINANCE_REST_URL, BINANCE_WS_URL, BITFINEX_REST_URL, BITFINEX_WS_URL, BITSTAMP_REST_URL, BITSTAMP_WS_URL, BookStream};
+use crate::{COINBASE_REST_URL, COINBASE_WS_URL, DEPTH, KRAKEN_REST_URL, KRAKEN_WS_URL, MAX_DEPTH};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+/// Settings read from a TOML file, e.g.
+///
+/// ```toml
+/// symbols = ["ETH/BTC", "LTC/BTC"]
+/// depth = 10
+/// bbo = false
+/// log_level = "info"
+///
+/// [bind]
+/// grpc = "[::1]:33333"
+/// metrics = "[::1]:9090"
+///
+/// [exchanges.binance]
+/// url = "wss://testnet.binance.vision"
+/// rest_url = "https://testnet.binance.vision/api/v3"
+/// stream = "diff"
+/// stale_after = 5.0
+///
+/// [exchanges.bitstamp]
+/// enabled = false
+///
+/// [exchanges.bitfinex]
+/// enabled = true
+/// precision = "P1"
+/// ```
+///
+/// Anything left out keeps its default. Bitstamp and Binance are enabled unless
+/// disabled, the exchanges added since need enabling.
+/// Any other name is a venue of its own, streamed as its `json` table describes.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub symbols: Vec<String>,
+
+    /// Levels per side of the book when a subscriber doesn't ask for a depth.
+    pub depth: usize,
+
+    /// Aggregates just the best bid and ask of every exchange.
+    pub bbo: bool,
+
+    /// Filter in the syntax of `RUST_LOG`, e.g. `info`.
+    pub log_level: Option<String>,
+
+    pub bind: Bind,
+
+    #[serde(deserialize_with = "exchanges")]
+    pub exchanges: BTreeMap<Exchange, ExchangeConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Bind {
+    pub grpc: SocketAddr,
+
+    /// Serves Prometheus metrics at `/metrics` if set.
+    pub metrics: Option<SocketAddr>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExchangeConfig {
+    /// Whether to aggregate the exchange, by default only Bitstamp, Binance and the
+    /// venues of their own do.
+    pub enabled: Option<bool>,
+
+    /// Websocket endpoint, the exchange's own if not set.
+    pub url: Option<String>,
+
+    /// REST endpoint of the listings and snapshots, the exchange's own if not set.
+    pub rest_url: Option<String>,
+
+    pub stream: BookStream,
+
+    /// Seconds without an update before the exchange is left out of the book.
+    pub stale_after: Option<f64>,
+
+    /// Precision of the book, `P0` if not set. Only Bitfinex aggregates its levels to one.
+    pub precision: Option<Precision>,
+
+    /// How a venue without an adapter of its own sends its books.
+    pub json: Option<JsonFormat>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            symbols: vec!["ETH/BTC".to_string()],
+            depth: DEPTH,
+            bbo: false,
+            log_level: None,
+            bind: Bind::default(),
+            exchanges: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for Bind {
+    fn default() -> Self {
+        Bind {
+            grpc: "[::1]:33333".parse().unwrap(),
+            metrics: None,
+        }
+    }
+}
+
+impl Default for ExchangeConfig {
+    fn default() -> Self {
+        ExchangeConfig {
+            enabled: None,
+            url: None,
+            rest_url: None,
+            stream: BookStream::Partial,
+            stale_after: None,
+            precision: None,
+            json: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, Error> {
+        let s = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&s)?)
+    }
+
+    /// The config of an exchange, to be changed in place.
+    pub fn exchange(&mut self, exchange: Exchange) -> &mut ExchangeConfig {
+        self.exchanges.entry(exchange).or_default()
+    }
+
+    /// Checks the config and turns it into the settings of the server.
+    pub fn settings(mut self) -> Result<Settings, Error> {
+        if self.depth == 0 || self.depth > MAX_DEPTH {
+            return Err(Error::BadConfig(format!("depth must be between 1 and {}", MAX_DEPTH)))
+        }
+        if self.symbols.is_empty() {
+            return Err(Error::BadConfig("no symbols to aggregate".to_string()))
+        }
+        let symbols: Vec<Instrument> = self.symbols.iter()
+            .map(|s| s.parse().map_err(Error::BadConfig))
+            .collect::<Result<_, _>>()?;
+
+        let generic: Vec<Exchange> = self.exchanges.keys()
+            .filter(|e| matches!(e, Exchange::Generic(_)))
+            .cloned()
+            .collect();
+
+        let mut exchanges = BTreeMap::new();
+        for exchange in Exchange::ALL.into_iter().chain(generic) {
+            let config = self.exchange(exchange.clone()).clone();
+            if !config.enabled.unwrap_or_else(|| enabled_by_default(&exchange)) {
+                continue
+            }
+            check_json(&exchange, &config, symbols.len())?;
+            if config.precision.is_some() && exchange != Exchange::Bitfinex {
+                return Err(Error::BadConfig(format!("{} doesn't aggregate its levels to a precision", exchange)))
+            }
+
+            let stale_after = match config.stale_after {
+                Some(secs) => Some(Duration::try_from_secs_f64(secs).ok().filter(|_| secs > 0.0).ok_or_else(|| {
+                    Error::BadConfig(format!("{}: stale_after must be positive, got {}", exchange, secs))
+                })?),
+                None => None,
+            };
+            let url = match config.url.or_else(|| default_url(&exchange).map(String::from)) {
+                Some(url) => url,
+                None => return Err(Error::BadConfig(format!("{}: no url", exchange))),
+            };
+            let rest_url = match (&exchange, config.rest_url) {
+                (Exchange::Generic(_), Some(_)) => {
+                    return Err(Error::BadConfig(format!("{} has no adapter of its own to use a rest_url", exchange)))
+                },
+                (_, rest_url) => rest_url.or_else(|| default_rest_url(&exchange).map(String::from)),
+            };
+
+            let settings = ExchangeSettings {
+                url,
+                rest_url,
+                stream: config.stream,
+                stale_after,
+                precision: config.precision,
+                json: config.json,
+            };
+            exchanges.insert(exchange, settings);
+        }
+        if exchanges.is_empty() {
+            return Err(Error::BadConfig("no exchanges enabled".to_string()))
+        }
+
+        Ok(Settings {
+            symbols,
+            depth: self.depth,
+            bbo: self.bbo,
+            grpc_addr: self.bind.grpc,
+            metrics_addr: self.bind.metrics,
+            exchanges,
+            record: None,
+            replay: None,
+        })
+    }
+}
+
+/// Reads the exchanges keyed by their names, any name but those of the exchanges
+/// with an adapter of their own being a venue of its own.
+fn exchanges<'de, D>(deserializer: D) -> Result<BTreeMap<Exchange, ExchangeConfig>, D::Error>
+    where D: Deserializer<'de>
+{
+    BTreeMap::<String, ExchangeConfig>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(name, config)| match name.parse() {
+            Ok(exchange) => Ok((exchange, config)),
+            Err(_) if !name.is_empty() && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_') => {
+                Ok((Exchange::Generic(name), config))
+            },
+            Err(_) => Err(D::Error::custom(format!(
+                "exchange {} must be named in lowercase letters, digits and underscores", name
+            ))),
+        })
+        .collect()
+}
+
+fn default_url(exchange: &Exchange) -> Option<&'static str> {
+    match exchange {
+        Exchange::Bitstamp => Some(BITSTAMP_WS_URL),
+        Exchange::Binance => Some(BINANCE_WS_URL),
+        Exchange::Kraken => Some(KRAKEN_WS_URL),
+        Exchange::Coinbase => Some(COINBASE_WS_URL),
+        Exchange::Bitfinex => Some(BITFINEX_WS_URL),
+        Exchange::Generic(_) => None,
+    }
+}
+
+fn default_rest_url(exchange: &Exchange) -> Option<&'static str> {
+    match exchange {
+        Exchange::Bitstamp => Some(BITSTAMP_REST_URL),
+        Exchange::Binance => Some(BINANCE_REST_URL),
+        Exchange::Kraken => Some(KRAKEN_REST_URL),
+        Exchange::Coinbase => Some(COINBASE_REST_URL),
+        Exchange::Bitfinex => Some(BITFINEX_REST_URL),
+        Exchange::Generic(_) => None,
+    }
+}
+
+/// Whether an exchange is aggregated unless the config says otherwise. The
+/// exchanges added after Bitstamp and Binance need enabling, so as not to turn
+/// on for existing deployments whose symbols they may not list. A venue of its
+/// own is enabled by its table.
+fn enabled_by_default(exchange: &Exchange) -> bool {
+    match exchange {
+        Exchange::Bitstamp | Exchange::Binance | Exchange::Generic(_) => true,
+        Exchange::Kraken | Exchange::Coinbase | Exchange::Bitfinex => false,
+    }
+}
+
+/// Checks that only the venues without an adapter of their own have a `json`
+/// table, and that it tells the symbols apart.
+fn check_json(exchange: &Exchange, config: &ExchangeConfig, symbols: usize) -> Result<(), Error> {
+    match (exchange, &config.json) {
+        (Exchange::Generic(_), None) => Err(Error::BadConfig(format!(
+            "unknown exchange {}, a venue of its own needs an [exchanges.{}.json] table", exchange, exchange
+        ))),
+        (Exchange::Generic(_), Some(json)) if json.symbol_pointer.is_none() && symbols > 1 => Err(Error::BadConfig(format!(
+            "{}: symbol_pointer is needed to tell {} symbols apart", exchange, symbols
+        ))),
+        (Exchange::Generic(_), Some(_)) | (_, None) => Ok(()),
+        (_, Some(_)) => Err(Error::BadConfig(format!("{} has an adapter of its own, it takes no json table", exchange))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn other_exchange_names_are_venues_of_their_own() {
+        let config: Config = toml::from_str(r#"
+            [exchanges.binance]
+            enabled = false
+
+            [exchanges.acme_2]
+            url = "ws://127.0.0.1:9448"
+        "#).unwrap();
+        let names: Vec<Exchange> = config.exchanges.into_keys().collect();
+        assert_eq!(names, vec![Exchange::Binance, Exchange::Generic("acme_2".to_string())]);
+    }
+
+    #[test]
+    fn depth_must_be_at_least_1() {
+        let config: Config = toml::from_str("depth = 0\n").unwrap();
+        assert!(matches!(config.settings(), Err(Error::BadConfig(e)) if e.starts_with("depth must be between 1")));
+    }
+
+    #[test]
+    fn stale_after_must_be_positive() {
+        for secs in ["0.0", "-1.0"] {
+            let config: Config = toml::from_str(&format!("[exchanges.binance]\nstale_after = {}\n", secs)).unwrap();
+            assert!(matches!(
+                config.settings(),
+                Err(Error::BadConfig(e)) if e == format!("binance: stale_after must be positive, got {}", secs.trim_end_matches(".0"))
+            ));
+        }
+    }
+
+    #[test]
+    fn exchanges_added_later_need_enabling() {
+        let enabled = |toml: &str| -> Vec<Exchange> {
+            toml::from_str::<Config>(toml).unwrap().settings().unwrap().exchanges.into_keys().collect()
+        };
+        assert_eq!(enabled(""), vec![Exchange::Bitstamp, Exchange::Binance]);
+        assert_eq!(enabled("[exchanges.kraken]\nurl = \"ws://127.0.0.1:9445\"\n"), vec![Exchange::Bitstamp, Exchange::Binance]);
+        assert_eq!(
+            enabled("[exchanges.kraken]\nenabled = true\n\n[exchanges.binance]\nenabled = false\n"),
+            vec![Exchange::Bitstamp, Exchange::Kraken],
+        );
+    }
+
+    #[test]
+    fn exchange_names_must_be_lowercase() {
+        let res = toml::from_str::<Config>("[exchanges.Binance]\nenabled = false\n");
+        assert!(res.unwrap_err().to_string().contains("exchange Binance must be named in lowercase"));
+    }
+}
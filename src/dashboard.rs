@@ -2,6 +2,7 @@ use clap::Parser;
 use proto::orderbook_aggregator_client::OrderbookAggregatorClient;
 use rust_decimal::Decimal;
 use rust_decimal::prelude::{FromPrimitive, ToPrimitive};
+use std::str::FromStr;
 
 // UI related uses
 use crossterm::{
@@ -39,6 +40,15 @@ mod proto {
 struct Cli {
     #[clap(short, long, help = "(Optional) Port number of the gRPC server. Default: 33333")]
     port: Option<usize>,
+
+    #[clap(short, long, help = "(Optional) Currency pair to stream, required when the server aggregates several")]
+    symbol: Option<String>,
+
+    #[clap(short, long, help = "(Optional) Levels per side, up to 100. Default: 10")]
+    depth: Option<u32>,
+
+    #[clap(short, long, use_value_delimiter = true, help = "(Optional) Exchanges to merge, repeated or comma separated. Default: all")]
+    exchange: Vec<String>,
 }
 
 #[derive(Clone)]
@@ -94,7 +104,13 @@ async fn run_app<B: Backend>(terminal: &mut Terminal<B>) -> Result<(), Box<dyn s
 
     let mut client = OrderbookAggregatorClient::connect(addr).await.unwrap();
 
-    let request = tonic::Request::new(proto::Empty {});
+    let symbol = args.symbol.unwrap_or_default();
+    let request = tonic::Request::new(proto::BookRequest {
+        symbol,
+        depth: args.depth.unwrap_or_default(),
+        exchanges: args.exchange,
+        side: proto::Side::Both as i32,
+    });
 
     let mut response = client.book_summary(request).await?.into_inner();
 
@@ -120,10 +136,10 @@ async fn run_app<B: Backend>(terminal: &mut Terminal<B>) -> Result<(), Box<dyn s
             }
         }
 
-        let proto::Summary{spread, bids, asks} = res;
+        let proto::Summary{spread, bids, asks, spread_exact, ..} = res;
 
         // set spread
-        let mut spread = Decimal::from_f64(spread).unwrap() ;
+        let mut spread = to_decimal(&spread_exact, spread) ;
         spread.rescale(8);
 
         // set bids
@@ -131,8 +147,8 @@ async fn run_app<B: Backend>(terminal: &mut Terminal<B>) -> Result<(), Box<dyn s
         bids.iter().for_each(|level|
             {
                 bid_data.insert(0, Datapoint {
-                    price: Decimal::from_f64(level.price).unwrap(),
-                    qty: Decimal::from_f64(level.amount).unwrap(),
+                    price: to_decimal(&level.price_exact, level.price),
+                    qty: to_decimal(&level.amount_exact, level.amount),
                     exchange: level.exchange.clone()
                 });
                 if bid_data.len() > 200 {
@@ -146,8 +162,8 @@ async fn run_app<B: Backend>(terminal: &mut Terminal<B>) -> Result<(), Box<dyn s
         asks.iter().for_each(|level|
             {
                 ask_data.insert(0, Datapoint {
-                    price: Decimal::from_f64(level.price).unwrap(),
-                    qty: Decimal::from_f64(level.amount).unwrap(),
+                    price: to_decimal(&level.price_exact, level.price),
+                    qty: to_decimal(&level.amount_exact, level.amount),
                     exchange: level.exchange.clone()
                 });
                 if ask_data.len() > 200 {
@@ -171,6 +187,14 @@ async fn run_app<B: Backend>(terminal: &mut Terminal<B>) -> Result<(), Box<dyn s
 
 }
 
+/// Reads the exact decimal sent by the server, falling back to the `f64` sent by
+/// servers that predate the exact fields, and to zero if that isn't finite.
+fn to_decimal(exact: &str, approx: f64) -> Decimal {
+    Decimal::from_str(exact).ok()
+        .or_else(|| Decimal::from_f64(approx))
+        .unwrap_or(Decimal::ZERO)
+}
+
 fn ui<B: Backend>(f: &mut Frame<B>, app: &App) {
 
     let chunks = Layout::default()
@@ -326,3 +350,16 @@ fn ui<B: Backend>(f: &mut Frame<B>, app: &App) {
     f.render_widget(paragraph, chunks[2]);
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn to_decimal_prefers_the_exact_value_and_never_panics() {
+        assert_eq!(to_decimal("0.06952", 0.0695), dec!(0.06952));
+        assert_eq!(to_decimal("", 0.5), dec!(0.5));
+        assert_eq!(to_decimal("", f64::NAN), Decimal::ZERO);
+        assert_eq!(to_decimal("", f64::INFINITY), Decimal::ZERO);
+    }
+}
@@ -1,25 +1,68 @@
+use std::fmt;
+use crate::instrument::Instrument;
+use crate::orderbook::Exchange;
+
 #[derive(Debug)]
 pub enum Error {
-    BadConnection(tungstenite::Error),
+    BadConnection(Box<tungstenite::Error>),
 
     BadData(serde_json::Error),
 
-    IoError(std::io::Error),
+    Io(std::io::Error),
+
+    Server(tonic::transport::Error),
 
-    ServerError(tonic::transport::Error),
+    Http(hyper::Error),
 
     BadAddr(std::net::AddrParseError),
+
+    BadRequest(reqwest::Error),
+
+    BadConfig(String),
+
+    /// The exchange doesn't list the instrument, or doesn't trade it.
+    NotListed { exchange: Exchange, instrument: Instrument },
+
+    /// The exchange refused a subscription, the instrument is the one subscribed
+    /// to if it can tell.
+    Rejected {
+        exchange: Exchange,
+        instrument: Option<Instrument>,
+        code: Option<String>,
+        message: String,
+    },
+
+    /// The exchange didn't confirm the subscription of the instruments in time.
+    Unconfirmed { exchange: Exchange, instruments: Vec<Instrument> },
+
+    /// A diff update doesn't follow on from the local book.
+    OutOfSync { expected: u64, got: u64 },
+
+    /// Nothing arrived for longer than the venue's heartbeats allow.
+    Silent(std::time::Duration),
 }
 
 #[derive(Debug)]
-pub enum ExchangeErr {
-    Bitstamp(Error),
-    Binance(Error),
+pub struct ExchangeErr {
+    pub(crate) exchange: Exchange,
+    pub(crate) error: Error,
+}
+
+impl ExchangeErr {
+    pub(crate) fn new(exchange: Exchange, error: Error) -> Self {
+        ExchangeErr { exchange, error }
+    }
+}
+
+impl fmt::Display for ExchangeErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {:?}", self.exchange, self.error)
+    }
 }
 
 impl From<tungstenite::Error> for Error {
     fn from(e: tungstenite::Error) -> Self {
-        Self::BadConnection(e)
+        Self::BadConnection(Box::new(e))
     }
 }
 
@@ -31,13 +74,19 @@ impl From<serde_json::Error> for Error {
 
 impl From<std::io::Error> for Error {
     fn from(e: std::io::Error) -> Self {
-        Self::IoError(e)
+        Self::Io(e)
     }
 }
 
 impl From<tonic::transport::Error> for Error {
     fn from(e: tonic::transport::Error) -> Self {
-        Self::ServerError(e)
+        Self::Server(e)
+    }
+}
+
+impl From<hyper::Error> for Error {
+    fn from(e: hyper::Error) -> Self {
+        Self::Http(e)
     }
 }
 
@@ -47,3 +96,15 @@ impl From<std::net::AddrParseError> for Error {
     }
 }
 
+impl From<toml::de::Error> for Error {
+    fn from(e: toml::de::Error) -> Self {
+        Self::BadConfig(e.to_string())
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Self::BadRequest(e)
+    }
+}
+
@@ -0,0 +1,348 @@
+use crate::adapter::ExchangeAdapter;
+use crate::error::Error;
+use crate::instrument::{Instrument, VenueInstrument};
+use crate::metrics;
+use crate::orderbook::{self, Exchange, InTick, TickTime};
+use crate::{MAX_DEPTH, websocket};
+use futures::SinkExt;
+use log::{debug, info};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use tungstenite::Message;
+
+/// How a venue without an adapter of its own sends its books, as snapshots of
+/// the top levels in JSON, e.g.
+///
+/// ```toml
+/// [exchanges.acme]
+/// url = "wss://ws.acme.example/v1"
+///
+/// [exchanges.acme.json]
+/// symbol = "{base}_{quote}"
+/// subscribe = '{"op": "subscribe", "channel": "book", "symbol": "{symbol}"}'
+/// message_type = { pointer = "/type", value = "book" }
+/// symbol_pointer = "/symbol"
+/// bids = "/data/bids"
+/// asks = "/data/asks"
+/// price = "price"
+/// amount = "size"
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JsonFormat {
+    /// The venue's name of a symbol, `{base}` and `{quote}` standing for its assets.
+    pub symbol: String,
+
+    /// Whether the venue's names are lowercase.
+    pub lowercase: bool,
+
+    /// Joins the names substituted for `{symbols}` in the url, for venues which
+    /// subscribe through it.
+    pub separator: String,
+
+    /// Sent for every symbol once connected, `{symbol}` standing for its name.
+    pub subscribe: Option<String>,
+
+    /// Which messages carry a book, all of them if not set.
+    pub message_type: Option<MessageType>,
+
+    /// JSON pointer to the name of the symbol a book is of, which may only be left
+    /// out for a single symbol.
+    pub symbol_pointer: Option<String>,
+
+    /// JSON pointers to the arrays of levels, best first.
+    pub bids: String,
+    pub asks: String,
+
+    /// Where the price and amount are in a level.
+    pub price: LevelField,
+    pub amount: LevelField,
+}
+
+/// Tells the messages carrying a book apart by the value at a JSON pointer.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MessageType {
+    pub pointer: String,
+    pub value: Value,
+}
+
+/// A position in a level given as an array, e.g. `["0.07", "1.5"]`, or the name
+/// of a field of one given as an object.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum LevelField {
+    Position(usize),
+    Name(String),
+}
+
+impl Default for JsonFormat {
+    fn default() -> Self {
+        JsonFormat {
+            symbol: "{base}{quote}".to_string(),
+            lowercase: false,
+            separator: ",".to_string(),
+            subscribe: None,
+            message_type: None,
+            symbol_pointer: None,
+            bids: "/bids".to_string(),
+            asks: "/asks".to_string(),
+            price: LevelField::Position(0),
+            amount: LevelField::Position(1),
+        }
+    }
+}
+
+impl JsonFormat {
+    /// The venue's name of an instrument, e.g. `eth_btc` for `ETH/BTC`.
+    fn venue_symbol(&self, instrument: &Instrument) -> String {
+        let symbol = self.symbol
+            .replace("{base}", &instrument.base)
+            .replace("{quote}", &instrument.quote);
+        match self.lowercase {
+            true => symbol.to_lowercase(),
+            false => symbol,
+        }
+    }
+}
+
+/// Streams a venue configured with a `JsonFormat`, every message carrying the
+/// top levels of a symbol.
+pub(crate) struct Adapter {
+    exchange: Exchange,
+    format: JsonFormat,
+
+    /// Instruments keyed by the venue's name, e.g. `eth_btc` -> `ETH/BTC`.
+    symbols: HashMap<String, VenueInstrument>,
+
+    /// Instruments subscribed to that didn't stream yet.
+    pending: Vec<Instrument>,
+
+    url: String,
+}
+
+impl Adapter {
+    pub(crate) fn new(exchange: Exchange, instruments: &[Instrument], url: &str, format: JsonFormat) -> Adapter {
+        let symbols = instruments.iter()
+            .map(|i| VenueInstrument::new(i, format.venue_symbol(i)))
+            .map(|i| (i.venue_symbol.clone(), i))
+            .collect();
+        Adapter {
+            exchange,
+            format,
+            symbols,
+            pending: vec![],
+            url: url.to_string(),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl ExchangeAdapter for Adapter {
+    fn exchange(&self) -> Exchange {
+        self.exchange.clone()
+    }
+
+    /// There's no listing to ask, the symbols stream as configured.
+    async fn resolve(&mut self) -> Result<(), Error> {
+        for instrument in self.symbols.values() {
+            info!("{} streams {} unchecked", self.exchange, instrument);
+        }
+        Ok(())
+    }
+
+    async fn connect(&mut self) -> Result<websocket::WsStream, Error> {
+        websocket::connect(self.url().as_str()).await
+    }
+
+    async fn subscribe(&mut self, ws_stream: &mut websocket::WsStream) -> Result<(), Error> {
+        self.pending = self.symbols.values().map(|i| i.instrument.clone()).collect();
+        if let Some(subscribe) = &self.format.subscribe {
+            for venue_symbol in self.symbols.keys() {
+                ws_stream.send(Message::Text(subscribe.replace("{symbol}", venue_symbol))).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Not knowing how the venue answers, a subscription is only confirmed by
+    /// its first book.
+    fn unconfirmed(&self) -> Vec<Instrument> {
+        self.pending.clone()
+    }
+
+    fn parse(&mut self, msg: Message) -> Result<Option<InTick>, Error> {
+        let text = match msg {
+            Message::Text(text) => text,
+            _ => return Ok(None),
+        };
+        let value: Value = serde_json::from_str(&text)
+            .inspect_err(|_| metrics::parse_error(self.exchange.clone()))?;
+        debug!("{}", value);
+
+        if let Some(message_type) = &self.format.message_type {
+            if value.pointer(&message_type.pointer) != Some(&message_type.value) {
+                return Ok(None)
+            }
+        }
+
+        let instrument = match &self.format.symbol_pointer {
+            Some(pointer) => value.pointer(pointer)
+                .and_then(Value::as_str)
+                .and_then(|symbol| self.symbols.get(symbol)),
+            None => self.symbols.values().next(),
+        };
+        let symbol = match instrument {
+            Some(instrument) => instrument.symbol(),
+            None => return Ok(None),
+        };
+
+        let bids = self.levels(&value, &self.format.bids, orderbook::Side::Bid)
+            .inspect_err(|_| metrics::parse_error(self.exchange.clone()))?;
+        let asks = self.levels(&value, &self.format.asks, orderbook::Side::Ask)
+            .inspect_err(|_| metrics::parse_error(self.exchange.clone()))?;
+
+        self.pending.retain(|i| i.to_string() != symbol);
+        Ok(Some(InTick { exchange: self.exchange.clone(), symbol, bids, asks, time: TickTime::new(None, None) }))
+    }
+}
+
+impl Adapter {
+    /// The url to connect to, with the names of all symbols in place of `{symbols}`.
+    fn url(&self) -> String {
+        let mut venue_symbols: Vec<&str> = self.symbols.keys().map(String::as_str).collect();
+        venue_symbols.sort();
+        self.url.replace("{symbols}", &venue_symbols.join(&self.format.separator))
+    }
+
+    /// The levels of a side at a JSON pointer, up to `MAX_DEPTH`.
+    fn levels(&self, value: &Value, pointer: &str, side: orderbook::Side) -> Result<Vec<orderbook::Level>, Error> {
+        let levels = value.pointer(pointer)
+            .and_then(Value::as_array)
+            .ok_or_else(|| Error::BadData(serde::de::Error::custom(format!("no levels at {}", pointer))))?;
+
+        levels.iter()
+            .take(MAX_DEPTH)
+            .map(|level| {
+                let price = field(level, &self.format.price)?;
+                let amount = field(level, &self.format.amount)?;
+                Ok(orderbook::Level::new(side.clone(), price, amount, self.exchange.clone()))
+            })
+            .collect()
+    }
+}
+
+/// A price or amount of a level, given as a string or a number.
+fn field(level: &Value, field: &LevelField) -> Result<Decimal, Error> {
+    let value = match field {
+        LevelField::Position(i) => level.get(i),
+        LevelField::Name(name) => level.get(name),
+    };
+    let value = value
+        .ok_or_else(|| Error::BadData(serde::de::Error::custom(format!("no {:?} in level {}", field, level))))?;
+    Ok(<Decimal as Deserialize>::deserialize(value)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn adapter(symbols: &[&str], format: &str) -> Adapter {
+        let instruments: Vec<Instrument> = symbols.iter().map(|s| s.parse().unwrap()).collect();
+        let format: JsonFormat = toml::from_str(format).unwrap();
+        let mut adapter = Adapter::new(Exchange::Generic("acme".to_string()), &instruments, "wss://acme/{symbols}", format);
+        adapter.pending = instruments;
+        adapter
+    }
+
+    fn parse(adapter: &mut Adapter, msg: &str) -> Option<InTick> {
+        adapter.parse(Message::Text(msg.to_string())).unwrap()
+    }
+
+    fn prices(levels: &[orderbook::Level]) -> Vec<Decimal> {
+        levels.iter().map(|l| l.price).collect()
+    }
+
+    #[test]
+    fn symbols_follow_the_template() {
+        let acme = adapter(&["ETH/BTC"], "symbol = \"{base}_{quote}\"\nlowercase = true\n");
+        assert_eq!(acme.symbols.keys().collect::<Vec<_>>(), vec!["eth_btc"]);
+
+        let acme = adapter(&["ETH/BTC"], "");
+        assert_eq!(acme.symbols.keys().collect::<Vec<_>>(), vec!["ETHBTC"]);
+    }
+
+    #[test]
+    fn url_names_every_symbol() {
+        let acme = adapter(&["LTC/BTC", "ETH/BTC"], "lowercase = true\nseparator = \"/\"\nsymbol_pointer = \"/s\"\n");
+        assert_eq!(acme.url(), "wss://acme/ethbtc/ltcbtc");
+
+        let acme = adapter(&["LTC/BTC", "ETH/BTC"], "symbol_pointer = \"/s\"\n");
+        assert_eq!(acme.url(), "wss://acme/ETHBTC,LTCBTC");
+    }
+
+    #[test]
+    fn reads_levels_of_arrays_by_position() {
+        let mut adapter = adapter(&["ETH/BTC"], "");
+        let tick = parse(&mut adapter, r#"{"bids": [["0.069", "1.5"], [0.068, 2]], "asks": [["0.070", "1"]]}"#).unwrap();
+
+        assert_eq!(tick.exchange, Exchange::Generic("acme".to_string()));
+        assert_eq!(tick.symbol, "ETH/BTC");
+        assert_eq!(prices(&tick.bids), vec![dec!(0.069), dec!(0.068)]);
+        assert_eq!(tick.bids[0].amount, dec!(1.5));
+        assert_eq!(tick.bids[1].amount, dec!(2));
+        assert_eq!(prices(&tick.asks), vec![dec!(0.070)]);
+        assert!(adapter.unconfirmed().is_empty());
+    }
+
+    #[test]
+    fn reads_levels_of_objects_by_name() {
+        let mut adapter = adapter(&["ETH/BTC"], r#"
+            bids = "/data/bids"
+            asks = "/data/asks"
+            price = "price"
+            amount = "size"
+        "#);
+        let tick = parse(&mut adapter, r#"{"data": {
+            "bids": [{"price": "0.069", "size": "1.5"}],
+            "asks": [{"price": "0.070", "size": "3"}]
+        }}"#).unwrap();
+
+        assert_eq!(prices(&tick.bids), vec![dec!(0.069)]);
+        assert_eq!(tick.asks[0].amount, dec!(3));
+
+        // a level without the field is bad data
+        let res = adapter.parse(Message::Text(r#"{"data": {"bids": [{"px": "1"}], "asks": []}}"#.to_string()));
+        assert!(matches!(res, Err(Error::BadData(_))));
+    }
+
+    #[test]
+    fn books_are_told_apart_by_type_and_symbol() {
+        let mut adapter = adapter(&["ETH/BTC", "LTC/BTC"], r#"
+            symbol = "{base}-{quote}"
+            message_type = { pointer = "/type", value = "book" }
+            symbol_pointer = "/symbol"
+        "#);
+
+        // other messages, and books of other symbols, are skipped
+        assert_eq!(parse(&mut adapter, r#"{"type": "trade", "symbol": "ETH-BTC", "bids": [], "asks": []}"#), None);
+        assert_eq!(parse(&mut adapter, r#"{"type": "book", "symbol": "XRP-BTC", "bids": [], "asks": []}"#), None);
+        assert_eq!(parse(&mut adapter, r#"{"type": "book", "bids": [], "asks": []}"#), None);
+        assert_eq!(adapter.unconfirmed().len(), 2);
+
+        let tick = parse(&mut adapter, r#"{"type": "book", "symbol": "LTC-BTC", "bids": [["0.002", "1"]], "asks": []}"#).unwrap();
+        assert_eq!(tick.symbol, "LTC/BTC");
+        assert_eq!(adapter.unconfirmed(), vec!["ETH/BTC".parse::<Instrument>().unwrap()]);
+    }
+
+    #[test]
+    fn levels_must_be_where_the_format_says() {
+        let mut adapter = adapter(&["ETH/BTC"], "");
+        let res = adapter.parse(Message::Text(r#"{"data": {"bids": [], "asks": []}}"#.to_string()));
+        assert!(matches!(res, Err(Error::BadData(_))));
+    }
+}
+
@@ -1,12 +1,17 @@
 use crate::error::Error;
-use crate::orderbook::{self, OutTick};
-use crate::ordermaster::OutTickPair;
+use crate::latency::{self, Histogram, Latency};
+use crate::metrics::ClientGuard;
+use crate::orderbook::{self, BookView, Exchange, Exchanges, OutTick, Side};
+use crate::ordermaster::{Books, LatencyPair, StatusPair};
+use crate::status::{ConnectionState, Status as FeedsStatus};
+use crate::MAX_DEPTH;
 use futures::Stream;
 use log::info;
 use rust_decimal::prelude::ToPrimitive;
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{RwLock, watch};
 use tonic::{transport::Server, Request, Response, Status};
 
 pub mod proto {
@@ -14,18 +19,45 @@ pub mod proto {
 }
 
 pub struct OrderBookService {
-    out_ticks: Arc<RwLock<OutTickPair>>,
+    books: Arc<RwLock<Books>>,
+    status: Arc<RwLock<StatusPair>>,
+    latency: Arc<RwLock<LatencyPair>>,
+
+    /// Levels per side when a subscriber doesn't ask for a depth.
+    depth: usize,
+
+    /// The exchanges aggregated, which a subscriber may pick from.
+    exchanges: Vec<Exchange>,
 }
 
 impl OrderBookService {
-    pub(crate) fn new(out_ticks: Arc<RwLock<OutTickPair>>) -> Self {
-        OrderBookService { out_ticks }
+    pub(crate) fn new(
+        books: Arc<RwLock<Books>>,
+        status: Arc<RwLock<StatusPair>>,
+        latency: Arc<RwLock<LatencyPair>>,
+        depth: usize,
+        exchanges: Vec<Exchange>,
+    ) -> Self
+    {
+        OrderBookService { books, status, latency, depth, exchanges }
     }
 
-    pub(crate) async fn serve(self, port: usize) -> Result<(), Error>{
-        let addr = format!("[::1]:{}", port);
-        let addr = addr.parse()?;
+    /// Returns a receiver of the orderbooks of the requested symbol. An empty symbol
+    /// picks the only symbol served, if there is just one.
+    async fn subscribe(&self, symbol: &str) -> Result<watch::Receiver<Exchanges>, Status> {
+        let books = self.books.read().await;
+
+        let pair = match (symbol.is_empty(), books.len()) {
+            (true, 1) => books.values().next(),
+            (true, _) => return Err(Status::invalid_argument("symbol is required")),
+            (false, _) => books.get(symbol),
+        };
+
+        pair.map(|(_, rx)| rx.clone())
+            .ok_or_else(|| Status::not_found(format!("symbol {} is not served", symbol)))
+    }
 
+    pub(crate) async fn serve(self, addr: SocketAddr) -> Result<(), Error>{
         info!("Serving grpc at {}", addr);
 
         Server::builder()
@@ -37,55 +69,273 @@ impl OrderBookService {
     }
 }
 
+/// Reads the view asked for, with the given default depth if none is, of the given
+/// exchanges aggregated.
+impl TryFrom<(&proto::BookRequest, usize, &[Exchange])> for BookView {
+    type Error = Status;
+
+    fn try_from((req, default_depth, aggregated): (&proto::BookRequest, usize, &[Exchange])) -> Result<Self, Self::Error> {
+        let depth = match req.depth as usize {
+            0 => default_depth,
+            d if d > MAX_DEPTH => {
+                return Err(Status::invalid_argument(format!("depth is limited to {}", MAX_DEPTH)))
+            },
+            d => d,
+        };
+
+        let exchanges = req.exchanges.iter()
+            .map(|name| aggregated.iter()
+                .find(|e| e.to_string() == *name)
+                .cloned()
+                .ok_or_else(|| format!("exchange {} is not aggregated", name)))
+            .collect::<Result<Vec<Exchange>, String>>()
+            .map_err(Status::invalid_argument)?;
+
+        let side = match proto::Side::from_i32(req.side) {
+            Some(proto::Side::Both) => None,
+            Some(proto::Side::Bid) => Some(Side::Bid),
+            Some(proto::Side::Ask) => Some(Side::Ask),
+            None => return Err(Status::invalid_argument(format!("unknown side {}", req.side))),
+        };
+
+        Ok(BookView { depth, exchanges, side })
+    }
+}
+
 impl From<OutTick> for proto::Summary {
     fn from(out_tick: OutTick) -> Self {
         let spread = out_tick.spread.to_f64().unwrap();
+        let spread_exact = out_tick.spread.to_string();
         let bids: Vec<proto::Level> = to_levels(&out_tick.bids);
         let asks: Vec<proto::Level> = to_levels(&out_tick.asks);
 
-        proto::Summary{ spread, bids, asks }
+        let sequence = out_tick.sequence;
+        let timestamp = out_tick.timestamp.timestamp_micros();
+        let excluded_exchanges = out_tick.excluded.iter().map(|e| e.to_string()).collect();
+        let venues = out_tick.venues.iter()
+            .map(|(exchange, time)|
+                proto::VenueTime {
+                    exchange: exchange.to_string(),
+                    exchange_timestamp: time.exchange.map_or(0, |t| t.timestamp_micros()),
+                    received_timestamp: time.received.timestamp_micros(),
+                    exchange_sequence: time.sequence.unwrap_or_default(),
+                })
+            .collect();
+
+        proto::Summary{
+            spread,
+            bids,
+            asks,
+            spread_exact,
+            sequence,
+            timestamp,
+            excluded_exchanges,
+            venues,
+        }
     }
 }
 
-fn to_levels(levels: &Vec<orderbook::Level>) -> Vec<proto::Level> {
+fn to_levels(levels: &[orderbook::Level]) -> Vec<proto::Level> {
     levels.iter()
         .map(|l|
             proto::Level{
                 exchange: l.exchange.to_string(),
                 price: l.price.to_f64().unwrap(),
                 amount: l.amount.to_f64().unwrap(),
+                price_exact: l.price.to_string(),
+                amount_exact: l.amount.to_string(),
             })
         .collect()
 }
 
+impl From<FeedsStatus> for proto::ServerStatus {
+    fn from(status: FeedsStatus) -> Self {
+        let feeds = status.feeds.into_iter()
+            .map(|(exchange, feed)| {
+                let state = match feed.state {
+                    ConnectionState::Connecting => proto::ConnectionState::Connecting,
+                    ConnectionState::Connected => proto::ConnectionState::Connected,
+                    ConnectionState::Disconnected => proto::ConnectionState::Disconnected,
+                };
+
+                proto::FeedStatus {
+                    exchange: exchange.to_string(),
+                    state: state as i32,
+                    last_message: feed.last_message.map_or(0, |t| t.timestamp_micros()),
+                    reconnects: feed.reconnects,
+                    messages: feed.messages,
+                    message_rate: feed.message_rate,
+                    parse_errors: feed.parse_errors,
+                    last_error: feed.last_error.unwrap_or_default(),
+                }
+            })
+            .collect();
+
+        proto::ServerStatus { feeds }
+    }
+}
+
+impl From<&Histogram> for proto::Histogram {
+    fn from(histogram: &Histogram) -> Self {
+        proto::Histogram {
+            bounds: latency::BOUNDS.to_vec(),
+            counts: histogram.counts.to_vec(),
+            count: histogram.count,
+            sum: histogram.sum.as_micros() as u64,
+            max: histogram.max.as_micros() as u64,
+        }
+    }
+}
+
+impl From<Latency> for proto::LatencyReport {
+    fn from(latency: Latency) -> Self {
+        let venues = latency.venues.iter()
+            .map(|(exchange, venue)|
+                proto::VenueLatency {
+                    exchange: exchange.to_string(),
+                    exchange_to_receive: Some((&venue.exchange_to_receive).into()),
+                    receive_to_publish: Some((&venue.receive_to_publish).into()),
+                })
+            .collect();
+
+        proto::LatencyReport { venues }
+    }
+}
+
 #[tonic::async_trait]
 impl proto::orderbook_aggregator_server::OrderbookAggregator for OrderBookService {
 
     type BookSummaryStream =
         Pin<Box<dyn Stream<Item = Result<proto::Summary, Status>> + Send + 'static>>;
 
+    type WatchStatusStream =
+        Pin<Box<dyn Stream<Item = Result<proto::ServerStatus, Status>> + Send + 'static>>;
+
     async fn book_summary(
         &self,
-        request: Request<proto::Empty>,
+        request: Request<proto::BookRequest>,
     ) -> Result<Response<Self::BookSummaryStream>, Status> {
         info!("Got a request: {:?}", request);
 
-        let _req = request.into_inner();
+        let req = request.into_inner();
+        let view = BookView::try_from((&req, self.depth, self.exchanges.as_slice()))?;
 
-        let mut rx_out_ticks = self.out_ticks.read().await.1.clone();
+        let mut rx_books = self.subscribe(&req.symbol).await?;
+        let client = ClientGuard::new();
 
         let output = async_stream::try_stream! {
+            // counts the client until the stream is dropped
+            let _client = client;
+
             // yield the current value
-            let out_tick = rx_out_ticks.borrow().clone();
-            yield proto::Summary::from(out_tick);
+            let mut last_tick = rx_books.borrow().to_tick(&view);
+            yield proto::Summary::from(last_tick.clone());
 
-            while let Ok(_) = rx_out_ticks.changed().await {
-                let out_tick = rx_out_ticks.borrow().clone();
-                yield proto::Summary::from(out_tick);
+            while rx_books.changed().await.is_ok() {
+                let out_tick = rx_books.borrow().to_tick(&view);
+
+                // the view may not be affected by the update
+                if !out_tick.same_levels(&last_tick) {
+                    last_tick = out_tick.clone();
+                    yield proto::Summary::from(out_tick);
+                }
             }
         };
 
         Ok(Response::new(Box::pin(output) as Self::BookSummaryStream))
     }
+
+    async fn get_book_snapshot(
+        &self,
+        request: Request<proto::BookRequest>,
+    ) -> Result<Response<proto::Summary>, Status> {
+        info!("Got a request: {:?}", request);
+
+        let req = request.into_inner();
+        let view = BookView::try_from((&req, self.depth, self.exchanges.as_slice()))?;
+
+        let rx_books = self.subscribe(&req.symbol).await?;
+        let out_tick = rx_books.borrow().to_tick(&view);
+
+        Ok(Response::new(proto::Summary::from(out_tick)))
+    }
+
+    async fn get_status(
+        &self,
+        request: Request<proto::Empty>,
+    ) -> Result<Response<proto::ServerStatus>, Status> {
+        info!("Got a request: {:?}", request);
+
+        let status = self.status.read().await.1.borrow().clone();
+
+        Ok(Response::new(proto::ServerStatus::from(status)))
+    }
+
+    async fn watch_status(
+        &self,
+        request: Request<proto::Empty>,
+    ) -> Result<Response<Self::WatchStatusStream>, Status> {
+        info!("Got a request: {:?}", request);
+
+        let mut rx_status = self.status.read().await.1.clone();
+        let client = ClientGuard::new();
+
+        let output = async_stream::try_stream! {
+            // counts the client until the stream is dropped
+            let _client = client;
+
+            // yield the current value
+            let status = rx_status.borrow().clone();
+            yield proto::ServerStatus::from(status);
+
+            while rx_status.changed().await.is_ok() {
+                let status = rx_status.borrow().clone();
+                yield proto::ServerStatus::from(status);
+            }
+        };
+
+        Ok(Response::new(Box::pin(output) as Self::WatchStatusStream))
+    }
+
+    async fn get_latency(
+        &self,
+        request: Request<proto::Empty>,
+    ) -> Result<Response<proto::LatencyReport>, Status> {
+        info!("Got a request: {:?}", request);
+
+        let latency = self.latency.read().await.1.borrow().clone();
+
+        Ok(Response::new(proto::LatencyReport::from(latency)))
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(exchanges: &[&str]) -> proto::BookRequest {
+        proto::BookRequest {
+            symbol: "ETH/BTC".to_string(),
+            depth: 0,
+            exchanges: exchanges.iter().map(|e| e.to_string()).collect(),
+            side: proto::Side::Both as i32,
+        }
+    }
+
+    #[test]
+    fn view_picks_from_the_exchanges_aggregated() {
+        let aggregated = [Exchange::Binance, Exchange::Generic("acme".to_string())];
+        let view = BookView::try_from((&request(&["acme", "binance"]), 10, aggregated.as_slice())).unwrap();
+        assert_eq!(view.exchanges, vec![Exchange::Generic("acme".to_string()), Exchange::Binance]);
+        assert_eq!(view.depth, 10);
+    }
+
+    #[test]
+    fn view_of_an_exchange_not_aggregated_is_invalid() {
+        let aggregated = [Exchange::Binance];
+        for name in ["binanse", "kraken", "acme"] {
+            let status = BookView::try_from((&request(&[name]), 10, aggregated.as_slice())).unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use crate::error::Error;
+use rust_decimal::Decimal;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// A currency pair, e.g. `ETH/BTC`, named the same whatever the exchange calls it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Instrument {
+    pub base: String,
+    pub quote: String,
+}
+
+impl FromStr for Instrument {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('/') {
+            Some((base, quote)) if !base.is_empty() && !quote.is_empty() && !quote.contains('/') => {
+                Ok(Instrument { base: base.to_uppercase(), quote: quote.to_uppercase() })
+            },
+            _ => Err(format!("symbol {} should look like BASE/QUOTE, e.g. ETH/BTC", s)),
+        }
+    }
+}
+
+impl fmt::Display for Instrument {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.base, self.quote)
+    }
+}
+
+/// Assets an exchange names differently, as canonical and venue name.
+pub(crate) type Aliases = &'static [(&'static str, &'static str)];
+
+impl Instrument {
+    /// Name of an asset of the pair on an exchange with the given aliases.
+    pub(crate) fn venue_asset(asset: &str, aliases: Aliases) -> &str {
+        aliases.iter()
+            .find(|(canonical, _)| *canonical == asset)
+            .map_or(asset, |(_, venue)| venue)
+    }
+}
+
+/// How an exchange lists an instrument.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct VenueInstrument {
+    pub(crate) instrument: Instrument,
+
+    /// The exchange's name of the instrument, e.g. `ethbtc`.
+    pub(crate) venue_symbol: String,
+
+    /// Smallest price increment, if the exchange told.
+    pub(crate) tick_size: Option<Decimal>,
+
+    /// Smallest amount increment, if the exchange told.
+    pub(crate) lot_size: Option<Decimal>,
+}
+
+impl VenueInstrument {
+    pub(crate) fn new(instrument: &Instrument, venue_symbol: String) -> Self {
+        VenueInstrument {
+            instrument: instrument.clone(),
+            venue_symbol,
+            tick_size: None,
+            lot_size: None,
+        }
+    }
+
+    /// The canonical symbol, e.g. `ETH/BTC`.
+    pub(crate) fn symbol(&self) -> String {
+        self.instrument.to_string()
+    }
+}
+
+impl fmt::Display for VenueInstrument {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} as {}", self.instrument, self.venue_symbol)?;
+        if let Some(tick_size) = self.tick_size {
+            write!(f, ", tick size {}", tick_size)?;
+        }
+        if let Some(lot_size) = self.lot_size {
+            write!(f, ", lot size {}", lot_size)?;
+        }
+        Ok(())
+    }
+}
+
+/// How long to wait for an exchange to tell which instruments it lists.
+const LISTING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Client for the requests asking an exchange about its instruments.
+pub(crate) fn listing_client() -> Result<reqwest::Client, Error> {
+    let client = reqwest::Client::builder()
+        .timeout(LISTING_TIMEOUT)
+        // Coinbase turns away requests without one
+        .user_agent(concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")))
+        .build()?;
+    Ok(client)
+}
@@ -0,0 +1,555 @@
+use chrono::{DateTime, TimeZone, Utc};
+use crate::adapter::ExchangeAdapter;
+use crate::error::Error;
+use crate::instrument::{self, Aliases, Instrument, VenueInstrument};
+use crate::metrics;
+use crate::orderbook::{self, Exchange, InTick, OrderDepthsMap, TickTime, ToLevel, ToLevels};
+use crate::{MAX_DEPTH, websocket};
+use futures::SinkExt;
+use log::{debug, info, warn};
+use rust_decimal::Decimal;
+use rust_decimal::prelude::ToPrimitive;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use tungstenite::Message;
+
+/// Depth of the book subscribed to, Kraken offers 10, 25, 100, 500 and 1000.
+const BOOK_DEPTH: usize = 100;
+
+/// Levels per side covered by the checksum.
+const CHECKSUM_DEPTH: usize = 10;
+
+/// Assets Kraken names differently.
+const ALIASES: Aliases = &[("BTC", "XBT"), ("DOGE", "XDG")];
+
+/// Streams the `book` channel, a snapshot followed by updates which are checked
+/// against Kraken's checksum of the top of the book, or the `spread` channel.
+pub(crate) struct Adapter {
+    /// Instruments keyed by their Kraken pair, e.g. `ETH/XBT` -> `ETH/BTC`.
+    pairs: HashMap<String, VenueInstrument>,
+
+    /// Pairs subscribed to but not answered yet.
+    pending: Vec<String>,
+
+    books: HashMap<String, LocalBook>,
+
+    /// Set once a book went out of sync, so it's subscribed to afresh.
+    resubscribe: bool,
+
+    /// Streams `spread`, the best bid and ask as they change, instead of the book.
+    bbo: bool,
+
+    url: String,
+
+    /// REST endpoint, e.g. `KRAKEN_REST_URL`.
+    rest_url: String,
+}
+
+impl Adapter {
+    pub(crate) fn new(instruments: &[Instrument], bbo: bool, url: &str, rest_url: &str) -> Adapter {
+        let pairs = instruments.iter()
+            .map(|i| VenueInstrument::new(i, venue_symbol(i)))
+            .map(|i| (i.venue_symbol.clone(), i))
+            .collect();
+        Adapter {
+            pairs,
+            pending: vec![],
+            books: HashMap::new(),
+            resubscribe: false,
+            bbo,
+            url: url.to_string(),
+            rest_url: rest_url.to_string(),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl ExchangeAdapter for Adapter {
+    fn exchange(&self) -> Exchange {
+        Exchange::Kraken
+    }
+
+    async fn resolve(&mut self) -> Result<(), Error> {
+        let client = instrument::listing_client()?;
+        let mut pairs = HashMap::new();
+        for (_, mut instrument) in self.pairs.drain() {
+            match listing(&client, &self.rest_url, &instrument.venue_symbol).await {
+                Ok(Some(pair)) => {
+                    instrument.venue_symbol = pair.wsname;
+                    instrument.tick_size = Some(pair.tick_size.normalize());
+                    instrument.lot_size = Some(Decimal::new(1, pair.lot_decimals));
+                    info!("Kraken lists {}", instrument);
+                },
+                Ok(None) => return Err(Error::NotListed {
+                    exchange: Exchange::Kraken,
+                    instrument: instrument.instrument,
+                }),
+                Err(e) => warn!("Failed to look up {} on Kraken, streaming {} unchecked: {:?}",
+                    instrument.symbol(), instrument.venue_symbol, e),
+            }
+            pairs.insert(instrument.venue_symbol.clone(), instrument);
+        }
+        self.pairs = pairs;
+        Ok(())
+    }
+
+    async fn connect(&mut self) -> Result<websocket::WsStream, Error> {
+        websocket::connect(self.url.as_str()).await
+    }
+
+    /// Subscribes to the book of every pair at once, each starts with a snapshot.
+    async fn subscribe(&mut self, ws_stream: &mut websocket::WsStream) -> Result<(), Error> {
+        self.books.clear();
+        self.resubscribe = false;
+        self.pending = self.pairs.keys().cloned().collect();
+
+        let subscription = match self.bbo {
+            true => Subscription { name: "spread".to_string(), depth: None },
+            false => Subscription { name: "book".to_string(), depth: Some(BOOK_DEPTH) },
+        };
+        let msg = serde_json::to_string(&Event::Subscribe { pair: self.pending.clone(), subscription })?;
+        ws_stream.send(Message::Text(msg)).await?;
+        Ok(())
+    }
+
+    /// Kraken answers every pair subscribed to with a `subscriptionStatus`.
+    fn unconfirmed(&self) -> Vec<Instrument> {
+        self.pending.iter()
+            .filter_map(|pair| self.pairs.get(pair))
+            .map(|i| i.instrument.clone())
+            .collect()
+    }
+
+    fn parse(&mut self, msg: Message) -> Result<Option<InTick>, Error> {
+        let text = match msg {
+            Message::Text(text) => text,
+            _ => return Ok(None),
+        };
+        let incoming: Incoming = serde_json::from_str(&text)
+            .inspect_err(|_| metrics::parse_error(Exchange::Kraken))?;
+
+        match incoming {
+            Incoming::Book(parts) if self.bbo => {
+                let (pair, spread) = spread(parts)
+                    .inspect_err(|_| metrics::parse_error(Exchange::Kraken))?;
+                debug!("{} {:?}", pair, spread);
+                Ok(self.pairs.get(&pair).map(|instrument| spread.to_tick(&instrument.symbol())))
+            },
+            Incoming::Book(parts) => {
+                let (pair, data) = book_data(parts)
+                    .inspect_err(|_| metrics::parse_error(Exchange::Kraken))?;
+                debug!("{} {:?}", pair, data);
+                self.apply(pair, data)
+            },
+            Incoming::Event(Event::SubscriptionStatus { pair, status, error_message }) => {
+                info!("Subscription of {:?} {}", pair, status);
+                self.pending.retain(|p| Some(p) != pair.as_ref());
+                match status.as_str() {
+                    "error" => Err(Error::Rejected {
+                        exchange: Exchange::Kraken,
+                        instrument: pair.and_then(|p| self.pairs.get(&p)).map(|i| i.instrument.clone()),
+                        code: None,
+                        message: error_message.unwrap_or_default(),
+                    }),
+                    _ => Ok(None),
+                }
+            },
+            Incoming::Event(Event::SystemStatus { status }) => {
+                info!("Kraken is {}", status);
+                Ok(None)
+            },
+            Incoming::Event(_) => Ok(None),
+        }
+    }
+
+    /// A book whose checksum didn't match is subscribed to again on a new connection.
+    fn reconnect_due(&self) -> bool {
+        self.resubscribe
+    }
+}
+
+impl Adapter {
+    fn apply(&mut self, pair: String, data: BookData) -> Result<Option<InTick>, Error> {
+        let instrument = match self.pairs.get(&pair) {
+            Some(instrument) => instrument,
+            None => return Ok(None),
+        };
+        if self.resubscribe {
+            return Ok(None)
+        }
+
+        let book = match data.is_snapshot() {
+            true => self.books.entry(pair).or_insert_with(LocalBook::new),
+            false => match self.books.get_mut(&pair) {
+                Some(book) => book,
+                None => return Ok(None),
+            },
+        };
+        book.apply(&data);
+
+        if let Some(expected) = data.checksum {
+            let got = book.checksum();
+            if got != expected {
+                warn!("Kraken checksum of {} is {}, expected {}, resubscribing", instrument.symbol(), got, expected);
+                self.books.clear();
+                self.resubscribe = true;
+                return Ok(None)
+            }
+        }
+
+        let time = TickTime::new(data.time(), None);
+        Ok(Some(book.depths.to_tick(Exchange::Kraken, &instrument.symbol(), MAX_DEPTH, time)))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Incoming {
+    /// `[channelID, {...}, ({...},) channelName, pair]`, the second object
+    /// carrying the bid updates if both sides changed, or `[channelID, [...],
+    /// "spread", pair]`.
+    Book(Vec<Value>),
+
+    Event(Event),
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "event")]
+enum Event {
+    #[serde(rename = "heartbeat")]
+    Heartbeat,
+
+    #[serde(rename = "systemStatus")]
+    SystemStatus { status: String },
+
+    #[serde(rename = "subscribe")]
+    Subscribe { pair: Vec<String>, subscription: Subscription },
+
+    #[serde(rename = "subscriptionStatus")]
+    SubscriptionStatus {
+        pair: Option<String>,
+        status: String,
+
+        #[serde(rename = "errorMessage")]
+        error_message: Option<String>,
+    },
+
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Subscription {
+    name: String,
+
+    /// Only for `book`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    depth: Option<usize>,
+}
+
+/// The levels of a book message, all of them in a snapshot or the changed ones in
+/// an update, which comes with a checksum.
+#[derive(Debug, Default, Deserialize)]
+struct BookData {
+    #[serde(rename = "bs", default)]
+    snapshot_bids: Vec<Level>,
+
+    #[serde(rename = "as", default)]
+    snapshot_asks: Vec<Level>,
+
+    #[serde(rename = "b", default)]
+    bids: Vec<Level>,
+
+    #[serde(rename = "a", default)]
+    asks: Vec<Level>,
+
+    #[serde(rename = "c", default, with = "checksum")]
+    checksum: Option<u32>,
+}
+
+impl BookData {
+    fn is_snapshot(&self) -> bool {
+        !self.snapshot_bids.is_empty() || !self.snapshot_asks.is_empty()
+    }
+
+    /// Time of the latest level changed.
+    fn time(&self) -> Option<DateTime<Utc>> {
+        self.snapshot_bids.iter()
+            .chain(&self.snapshot_asks)
+            .chain(&self.bids)
+            .chain(&self.asks)
+            .map(|l| l.timestamp)
+            .max()
+            .and_then(time)
+    }
+
+    fn merge(&mut self, other: BookData) {
+        self.snapshot_bids.extend(other.snapshot_bids);
+        self.snapshot_asks.extend(other.snapshot_asks);
+        self.bids.extend(other.bids);
+        self.asks.extend(other.asks);
+        self.checksum = self.checksum.or(other.checksum);
+    }
+}
+
+/// `[price, volume, timestamp]`, followed by `r` for a level republished
+/// after a deeper one moved into view.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "Vec<String>")]
+struct Level {
+    price: Decimal,
+    volume: Decimal,
+    timestamp: Decimal,
+}
+
+impl TryFrom<Vec<String>> for Level {
+    type Error = String;
+
+    fn try_from(fields: Vec<String>) -> Result<Self, Self::Error> {
+        match fields.as_slice() {
+            [price, volume, timestamp, ..] => Ok(Level {
+                price: price.parse().map_err(|e| format!("price {}: {}", price, e))?,
+                volume: volume.parse().map_err(|e| format!("volume {}: {}", volume, e))?,
+                timestamp: timestamp.parse().map_err(|e| format!("timestamp {}: {}", timestamp, e))?,
+            }),
+            _ => Err(format!("expected a level, got {:?}", fields)),
+        }
+    }
+}
+
+impl ToLevel for Level {
+    fn to_level(&self, side: orderbook::Side) -> orderbook::Level {
+        orderbook::Level::new(side, self.price, self.volume, Exchange::Kraken)
+    }
+}
+
+/// `[bid, ask, timestamp, bidVolume, askVolume]`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "Vec<String>")]
+struct Spread {
+    bid: Decimal,
+    ask: Decimal,
+    timestamp: Decimal,
+    bid_volume: Decimal,
+    ask_volume: Decimal,
+}
+
+impl TryFrom<Vec<String>> for Spread {
+    type Error = String;
+
+    fn try_from(fields: Vec<String>) -> Result<Self, Self::Error> {
+        let parsed: Vec<Decimal> = fields.iter()
+            .map(|f| f.parse().map_err(|e| format!("{}: {}", f, e)))
+            .collect::<Result<_, _>>()?;
+        match parsed.as_slice() {
+            [bid, ask, timestamp, bid_volume, ask_volume, ..] => Ok(Spread {
+                bid: *bid,
+                ask: *ask,
+                timestamp: *timestamp,
+                bid_volume: *bid_volume,
+                ask_volume: *ask_volume,
+            }),
+            _ => Err(format!("expected a spread, got {:?}", fields)),
+        }
+    }
+}
+
+impl Spread {
+    fn to_tick(&self, symbol: &str) -> InTick {
+        InTick {
+            exchange: Exchange::Kraken,
+            symbol: symbol.to_string(),
+            bids: vec![orderbook::Level::new(orderbook::Side::Bid, self.bid, self.bid_volume, Exchange::Kraken)],
+            asks: vec![orderbook::Level::new(orderbook::Side::Ask, self.ask, self.ask_volume, Exchange::Kraken)],
+            time: TickTime::new(time(self.timestamp), None),
+        }
+    }
+}
+
+/// Seconds since the Unix epoch, e.g. `1534614057.321597`.
+fn time(secs: Decimal) -> Option<DateTime<Utc>> {
+    (secs * Decimal::new(1_000_000, 0)).to_i64()
+        .and_then(|micros| Utc.timestamp_micros(micros).single())
+}
+
+/// The local book of a pair, kept to the depth subscribed to as Kraken expects.
+#[derive(Debug)]
+struct LocalBook {
+    depths: OrderDepthsMap,
+}
+
+impl LocalBook {
+    fn new() -> Self {
+        LocalBook { depths: OrderDepthsMap::with_depth(BOOK_DEPTH) }
+    }
+
+    fn apply(&mut self, data: &BookData) {
+        if data.is_snapshot() {
+            self.depths.reset(
+                data.snapshot_bids.to_levels(orderbook::Side::Bid, BOOK_DEPTH),
+                data.snapshot_asks.to_levels(orderbook::Side::Ask, BOOK_DEPTH),
+            );
+        }
+        self.depths.extend(
+            data.bids.to_levels(orderbook::Side::Bid, data.bids.len()),
+            data.asks.to_levels(orderbook::Side::Ask, data.asks.len()),
+        );
+    }
+
+    /// CRC32 of the top ten asks, best first, then the top ten bids, each as its
+    /// price and volume with the decimal point and leading zeros taken out.
+    fn checksum(&self) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        let levels = self.depths.best(orderbook::Side::Ask, CHECKSUM_DEPTH).into_iter()
+            .chain(self.depths.best(orderbook::Side::Bid, CHECKSUM_DEPTH));
+        for level in levels {
+            hasher.update(checksum_digits(&level.price).as_bytes());
+            hasher.update(checksum_digits(&level.amount).as_bytes());
+        }
+        hasher.finalize()
+    }
+}
+
+/// `0.05005` -> `5005`, relying on the decimal keeping the scale Kraken sent.
+fn checksum_digits(d: &Decimal) -> String {
+    d.to_string().replace('.', "").trim_start_matches('0').to_string()
+}
+
+/// Splits a book message into its pair and levels.
+fn book_data(parts: Vec<Value>) -> Result<(String, BookData), Error> {
+    let mut pair = None;
+    let mut data = BookData::default();
+    for part in parts.into_iter().skip(1) {
+        match part {
+            Value::Object(_) => data.merge(serde_json::from_value(part)?),
+            // the channel name comes before the pair
+            Value::String(s) => pair = Some(s),
+            _ => {},
+        }
+    }
+    match pair {
+        Some(pair) => Ok((pair, data)),
+        None => Err(Error::BadData(serde::de::Error::custom("book message without a pair"))),
+    }
+}
+
+/// Splits a spread message into its pair and spread.
+fn spread(parts: Vec<Value>) -> Result<(String, Spread), Error> {
+    match parts.as_slice() {
+        [_, spread, _, Value::String(pair)] => Ok((pair.clone(), serde_json::from_value(spread.clone())?)),
+        _ => Err(Error::BadData(serde::de::Error::custom("spread message without a pair"))),
+    }
+}
+
+/// Kraken's name of an instrument, e.g. `ETH/XBT` for `ETH/BTC`.
+fn venue_symbol(instrument: &Instrument) -> String {
+    format!(
+        "{}/{}",
+        Instrument::venue_asset(&instrument.base, ALIASES),
+        Instrument::venue_asset(&instrument.quote, ALIASES),
+    )
+}
+
+/// The answer of `/AssetPairs`.
+#[derive(Debug, Deserialize)]
+struct AssetPairs {
+    error: Vec<String>,
+
+    #[serde(default)]
+    result: HashMap<String, AssetPair>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AssetPair {
+    /// The pair's name on the websocket, e.g. `ETH/XBT`.
+    wsname: String,
+    tick_size: Decimal,
+    lot_decimals: u32,
+}
+
+/// How Kraken lists a pair, none if it doesn't.
+async fn listing(client: &reqwest::Client, rest_url: &str, pair: &str) -> Result<Option<AssetPair>, Error> {
+    let url = format!("{}/AssetPairs?pair={}", rest_url, pair.replace('/', ""));
+    let pairs: AssetPairs = client.get(url).send().await?
+        .error_for_status()?
+        .json().await?;
+
+    if pairs.error.iter().any(|e| e.contains("Unknown asset pair")) {
+        return Ok(None)
+    }
+    if !pairs.error.is_empty() {
+        return Err(Error::BadData(serde::de::Error::custom(pairs.error.join(", "))))
+    }
+    Ok(pairs.result.into_values().next())
+}
+
+mod checksum {
+    use serde::{Deserialize, Deserializer};
+
+    /// The checksum is sent as a decimal string.
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+        where D: Deserializer<'de>
+    {
+        let s = Option::<String>::deserialize(deserializer)?;
+        s.map(|s| s.parse().map_err(serde::de::Error::custom)).transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+    use serde_json::json;
+
+    /// The book of Kraken's checksum guide, whose checksum is 974947235.
+    const ASKS: [&str; 10] = ["0.05005", "0.05010", "0.05015", "0.05020", "0.05025", "0.05030", "0.05035", "0.05040", "0.05045", "0.05050"];
+    const BIDS: [&str; 10] = ["0.05000", "0.04995", "0.04990", "0.04980", "0.04975", "0.04970", "0.04965", "0.04960", "0.04955", "0.04950"];
+
+    fn adapter() -> Adapter {
+        Adapter::new(&["ETH/BTC".parse().unwrap()], false, "ws://127.0.0.1:1", "http://127.0.0.1:1")
+    }
+
+    fn levels(prices: &[&str]) -> Value {
+        prices.iter().map(|price| json!([price, "0.00000500", "1534614057.321597"])).collect()
+    }
+
+    fn message(data: Value) -> Message {
+        Message::Text(json!([336, data, "book-100", "ETH/XBT"]).to_string())
+    }
+
+    /// The book of the guide, but for a bid at 0.04985 deleted by the update.
+    fn snapshot() -> Message {
+        let mut bids = BIDS.to_vec();
+        bids.insert(3, "0.04985");
+        message(json!({ "as": levels(&ASKS), "bs": levels(&bids) }))
+    }
+
+    fn update(checksum: &str) -> Message {
+        message(json!({ "b": [["0.04985", "0.00000000", "1534614248.765567"]], "c": checksum }))
+    }
+
+    #[test]
+    fn checksum_of_the_guide_matches() {
+        let mut adapter = adapter();
+        assert!(adapter.parse(snapshot()).unwrap().is_some());
+
+        let tick = adapter.parse(update("974947235")).unwrap().unwrap();
+        assert!(!adapter.reconnect_due());
+        assert_eq!(tick.bids.len(), 10);
+        assert_eq!(tick.bids[3].price, dec!(0.04980));
+        assert_eq!(tick.asks[0].price, dec!(0.05005));
+    }
+
+    #[test]
+    fn checksum_mismatch_resubscribes() {
+        let mut adapter = adapter();
+        adapter.parse(snapshot()).unwrap();
+
+        assert!(adapter.parse(update("974947236")).unwrap().is_none());
+        assert!(adapter.reconnect_due());
+
+        // the books are out of sync until subscribed to again
+        assert!(adapter.parse(snapshot()).unwrap().is_none());
+    }
+}
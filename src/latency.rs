@@ -0,0 +1,126 @@
+use chrono::{DateTime, Utc};
+use crate::orderbook::Exchange;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+/// Upper bounds of the histogram buckets in microseconds, the last bucket is unbounded.
+pub(crate) const BOUNDS: [u64; 15] = [
+    100, 250, 500,
+    1_000, 2_500, 5_000,
+    10_000, 25_000, 50_000,
+    100_000, 250_000, 500_000,
+    1_000_000, 2_500_000, 5_000_000,
+];
+
+/// Distribution of latencies in fixed, roughly logarithmic buckets.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Histogram {
+    pub(crate) counts: [u64; BOUNDS.len() + 1],
+    pub(crate) count: u64,
+    pub(crate) sum: Duration,
+    pub(crate) max: Duration,
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram {
+            counts: [0; BOUNDS.len() + 1],
+            count: 0,
+            sum: Duration::ZERO,
+            max: Duration::ZERO,
+        }
+    }
+
+    fn record(&mut self, latency: Duration) {
+        let micros = latency.as_micros() as u64;
+        let bucket = BOUNDS.iter().position(|b| micros <= *b).unwrap_or(BOUNDS.len());
+        self.counts[bucket] += 1;
+        self.count += 1;
+        self.sum += latency;
+        self.max = self.max.max(latency);
+    }
+
+    /// Records the time from `from` to `to`. Clocks running apart may make it
+    /// negative, which is recorded as zero.
+    fn record_between(&mut self, from: DateTime<Utc>, to: DateTime<Utc>) {
+        self.record((to - from).to_std().unwrap_or_default());
+    }
+
+    /// Upper bound of the bucket holding the `q` quantile, `max` for the last bucket.
+    pub(crate) fn quantile(&self, q: f64) -> Duration {
+        let rank = (self.count as f64 * q).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return match BOUNDS.get(i) {
+                    Some(bound) => Duration::from_micros(*bound).min(self.max),
+                    None => self.max,
+                }
+            }
+        }
+        Duration::ZERO
+    }
+}
+
+impl fmt::Display for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.count {
+            0 => write!(f, "n=0"),
+            n => write!(
+                f,
+                "n={} mean={:?} p50<={:?} p99<={:?} max={:?}",
+                n,
+                self.sum / n as u32,
+                self.quantile(0.5),
+                self.quantile(0.99),
+                self.max,
+            ),
+        }
+    }
+}
+
+/// Latencies of the ticks of one exchange.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct VenueLatency {
+    /// From the event time reported by the exchange to the tick being parsed here.
+    pub(crate) exchange_to_receive: Histogram,
+
+    /// From the tick being parsed to the merged book being published to subscribers.
+    pub(crate) receive_to_publish: Histogram,
+}
+
+/// Latencies of every exchange since the server started.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Latency {
+    pub(crate) venues: BTreeMap<Exchange, VenueLatency>,
+}
+
+impl Latency {
+    pub(crate) fn new() -> Latency {
+        Latency { venues: BTreeMap::new() }
+    }
+
+    /// Records a tick received at `received` and published at `published`. Exchanges
+    /// that don't report an event time only get the latter.
+    pub(crate) fn record(
+        &mut self,
+        exchange: &Exchange,
+        event_time: Option<DateTime<Utc>>,
+        received: DateTime<Utc>,
+        published: DateTime<Utc>,
+    )
+    {
+        let venue = self.venues.entry(exchange.clone())
+            .or_insert_with(|| VenueLatency {
+                exchange_to_receive: Histogram::new(),
+                receive_to_publish: Histogram::new(),
+            });
+
+        if let Some(event_time) = event_time {
+            venue.exchange_to_receive.record_between(event_time, received);
+        }
+        venue.receive_to_publish.record_between(received, published);
+    }
+}
@@ -1,11 +1,56 @@
+mod adapter;
+mod backoff;
 mod binance;
+mod bitfinex;
 mod bitstamp;
+mod coinbase;
+mod config;
 mod error;
+mod generic;
 mod grpc;
+mod instrument;
+mod kraken;
+mod latency;
+mod metrics;
 mod orderbook;
+mod record;
+mod replay;
+mod status;
 mod websocket;
+#[cfg(feature = "mock")]
+pub mod mock;
 pub mod ordermaster;
 
+pub use bitfinex::Precision;
+pub use config::Config;
+pub use error::Error;
+pub use generic::JsonFormat;
+pub use grpc::proto;
+pub use instrument::Instrument;
+pub use orderbook::{Exchange, StaleAfter};
+pub use replay::Replay;
+
 pub const DEPTH:usize = 10 ;
-pub const BINANCE_WS_URL: &str = "wss://stream.binance.com:9443/ws";
+/// Deepest view a subscriber may ask for, exchanges forward their books up to it.
+pub const MAX_DEPTH: usize = 100;
+pub const BINANCE_WS_URL: &str = "wss://stream.binance.com:9443";
+pub const BINANCE_REST_URL: &str = "https://api.binance.com/api/v3";
 pub const BITSTAMP_WS_URL: &str = "wss://ws.bitstamp.net";
+pub const BITSTAMP_REST_URL: &str = "https://www.bitstamp.net/api/v2";
+pub const KRAKEN_WS_URL: &str = "wss://ws.kraken.com";
+pub const KRAKEN_REST_URL: &str = "https://api.kraken.com/0/public";
+pub const COINBASE_WS_URL: &str = "wss://ws-feed.exchange.coinbase.com";
+pub const COINBASE_REST_URL: &str = "https://api.exchange.coinbase.com";
+pub const BITFINEX_WS_URL: &str = "wss://api-pub.bitfinex.com/ws/2";
+pub const BITFINEX_REST_URL: &str = "https://api-pub.bitfinex.com/v2";
+
+/// Which kind of order book stream to consume from an exchange.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ArgEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BookStream {
+    /// Top levels sent as a full snapshot with every message.
+    Partial,
+
+    /// Diff updates applied to a local book, synchronized against a REST snapshot.
+    Diff,
+}
@@ -1,23 +1,249 @@
-use clap::Parser;
+use clap::{CommandFactory, ErrorKind, Parser};
 use keyrock_orders::ordermaster;
+use keyrock_orders::{BookStream, Config, Exchange, Precision, Replay};
+use log::error;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Parser)]
 struct Cli {
-    #[clap(short, long, help = "(Optional) Currency pair to subscribe to. Default: ETH/BTC")]
-    symbol: Option<String>,
+    #[clap(short, long, parse(from_os_str), help = "(Optional) TOML file with the settings of the server, which the other options override")]
+    config: Option<PathBuf>,
 
-    #[clap(short, long, help = "(Optional) Port number on which the the gRPC server will be hosted. Default: 50051")]
-    port: Option<usize>,
+    #[clap(short, long, use_value_delimiter = true, help = "(Optional) Currency pairs to subscribe to, repeated or comma separated. Default: ETH/BTC")]
+    symbol: Vec<String>,
 
+    #[clap(short, long, help = "(Optional) Port number on which the the gRPC server will be hosted. Default: 33333")]
+    port: Option<u16>,
+
+    #[clap(short, long, help = "(Optional) Levels per side of the book when a client doesn't ask for a depth. Default: 10")]
+    depth: Option<usize>,
+
+    #[clap(long, help = "(Optional) Aggregates just the best bid and ask of every exchange, from its fastest top of book feed where it has one")]
+    bbo: bool,
+
+    #[clap(short, long, use_value_delimiter = true, help = "(Optional) Exchanges to aggregate, repeated or comma separated. Default: bitstamp, binance and the venues of the config")]
+    exchange: Vec<String>,
+
+    #[clap(long, arg_enum, help = "(Optional) Bitstamp order book stream to consume. Default: partial")]
+    bitstamp_stream: Option<BookStream>,
+
+    #[clap(long, arg_enum, help = "(Optional) Binance order book stream to consume. Default: partial")]
+    binance_stream: Option<BookStream>,
+
+    #[clap(long, arg_enum, help = "(Optional) Precision of the Bitfinex order book, from five significant digits at P0 to one at P4. Default: P0")]
+    bitfinex_precision: Option<Precision>,
+
+    #[clap(long, help = "(Optional) Websocket endpoint of Bitstamp. Default: wss://ws.bitstamp.net")]
+    bitstamp_url: Option<String>,
+
+    #[clap(long, help = "(Optional) Websocket endpoint of Binance. Default: wss://stream.binance.com:9443")]
+    binance_url: Option<String>,
+
+    #[clap(long, help = "(Optional) Websocket endpoint of Kraken. Default: wss://ws.kraken.com")]
+    kraken_url: Option<String>,
+
+    #[clap(long, help = "(Optional) Websocket endpoint of Coinbase. Default: wss://ws-feed.exchange.coinbase.com")]
+    coinbase_url: Option<String>,
+
+    #[clap(long, help = "(Optional) Websocket endpoint of Bitfinex. Default: wss://api-pub.bitfinex.com/ws/2")]
+    bitfinex_url: Option<String>,
+
+    #[clap(long, help = "(Optional) REST endpoint of Bitstamp. Default: https://www.bitstamp.net/api/v2")]
+    bitstamp_rest_url: Option<String>,
+
+    #[clap(long, help = "(Optional) REST endpoint of Binance. Default: https://api.binance.com/api/v3")]
+    binance_rest_url: Option<String>,
+
+    #[clap(long, help = "(Optional) REST endpoint of Kraken. Default: https://api.kraken.com/0/public")]
+    kraken_rest_url: Option<String>,
+
+    #[clap(long, help = "(Optional) REST endpoint of Coinbase. Default: https://api.exchange.coinbase.com")]
+    coinbase_rest_url: Option<String>,
+
+    #[clap(long, help = "(Optional) REST endpoint of Bitfinex. Default: https://api-pub.bitfinex.com/v2")]
+    bitfinex_rest_url: Option<String>,
+
+    #[clap(long, parse(try_from_str = parse_stale_after), use_value_delimiter = true, help = "(Optional) Leaves an exchange out of the book when it didn't update for that many seconds, e.g. binance=5. Default: never")]
+    stale_after: Vec<(String, Duration)>,
+
+    #[clap(long, help = "(Optional) Port number on which Prometheus metrics are served at /metrics. Default: not served")]
+    metrics_port: Option<u16>,
+
+    #[clap(long, parse(from_os_str), help = "(Optional) Directory to record the raw websocket messages of every exchange to. Default: not recorded")]
+    record: Option<PathBuf>,
+
+    #[clap(long, parse(from_os_str), conflicts_with = "record", help = "(Optional) Directory of a recording to stream from instead of the exchanges. Default: stream live")]
+    replay: Option<PathBuf>,
+
+    #[clap(long, requires = "replay", help = "(Optional) How many times faster than recorded to replay. Default: 1")]
+    replay_speed: Option<f64>,
+
+    #[clap(long, requires = "replay", conflicts_with = "replay-speed", help = "(Optional) Replays as fast as possible")]
+    replay_max_speed: bool,
+
+    #[clap(long, help = "(Optional) Log filter in the syntax of RUST_LOG, e.g. info. Default: RUST_LOG")]
+    log_level: Option<String>,
+}
+
+/// Reads `EXCHANGE=SECONDS`, the exchange being checked against the config later.
+fn parse_stale_after(s: &str) -> Result<(String, Duration), String> {
+    let (exchange, secs) = s.split_once('=')
+        .ok_or_else(|| format!("expected EXCHANGE=SECONDS, got {}", s))?;
+    let timeout = secs.parse::<f64>()
+        .map_err(|e| e.to_string())
+        .and_then(|s| match s > 0.0 {
+            true => Duration::try_from_secs_f64(s).map_err(|_| "must be positive".to_string()),
+            false => Err("must be positive".to_string()),
+        })
+        .map_err(|e| format!("{}: {}", secs, e))?;
+    if exchange.is_empty() {
+        return Err(format!("expected EXCHANGE=SECONDS, got {}", s))
+    }
+    Ok((exchange.to_string(), timeout))
 }
 
 #[tokio::main]
 async fn main() {
-    env_logger::init();
     let args = Cli::parse();
-    let symbol: String = args.symbol.unwrap_or("ETH/BTC".to_string());
-    let port: usize = args.port.unwrap_or(33333);
 
-    ordermaster::run(&symbol, port).await.unwrap();
+    let mut config = match &args.config {
+        Some(path) => Config::load(path).unwrap_or_else(|e| {
+            Cli::command().error(ErrorKind::Io, format!("{}: {:?}", path.display(), e)).exit()
+        }),
+        None => Config::default(),
+    };
+    apply(&mut config, &args).unwrap_or_else(|e| {
+        Cli::command().error(ErrorKind::InvalidValue, e).exit()
+    });
+
+    let mut logger = env_logger::Builder::from_default_env();
+    if let Some(level) = &config.log_level {
+        logger.parse_filters(level);
+    }
+    logger.init();
+
+    let mut settings = config.settings().unwrap_or_else(|e| {
+        Cli::command().error(ErrorKind::InvalidValue, format!("{:?}", e)).exit()
+    });
+
+    // a diff stream would be synchronized against a live snapshot, which doesn't match the recording
+    if args.replay.is_some() && settings.exchanges.values().any(|e| e.stream == BookStream::Diff) {
+        Cli::command()
+            .error(ErrorKind::ArgumentConflict, "only partial streams can be replayed")
+            .exit();
+    }
+
+    settings.record = args.record;
+    settings.replay = args.replay.map(|dir| Replay {
+        dir,
+        speed: match args.replay_max_speed {
+            true => None,
+            false => Some(args.replay_speed.unwrap_or(1.0)),
+        },
+    });
+
+    if let Err(e) = ordermaster::run(settings).await {
+        error!("{:?}", e);
+        std::process::exit(1)
+    }
 }
 
+/// Overrides the config with the options given on the command line.
+fn apply(config: &mut Config, args: &Cli) -> Result<(), String> {
+    if !args.symbol.is_empty() {
+        config.symbols = args.symbol.clone();
+    }
+    if let Some(depth) = args.depth {
+        config.depth = depth;
+    }
+    if args.bbo {
+        config.bbo = true;
+    }
+    if let Some(port) = args.port {
+        config.bind.grpc.set_port(port);
+    }
+    if let Some(port) = args.metrics_port {
+        let mut addr = config.bind.metrics.unwrap_or_else(|| SocketAddr::new(config.bind.grpc.ip(), port));
+        addr.set_port(port);
+        config.bind.metrics = Some(addr);
+    }
+    if args.log_level.is_some() {
+        config.log_level = args.log_level.clone();
+    }
+
+    let enabled: Vec<Exchange> = args.exchange.iter()
+        .map(|name| exchange(config, name))
+        .collect::<Result<_, _>>()?;
+    let stale_after: Vec<(Exchange, Duration)> = args.stale_after.iter()
+        .map(|(name, timeout)| Ok((exchange(config, name)?, *timeout)))
+        .collect::<Result<_, String>>()?;
+
+    let generic: Vec<Exchange> = config.exchanges.keys()
+        .filter(|e| matches!(e, Exchange::Generic(_)))
+        .cloned()
+        .collect();
+
+    for exchange in Exchange::ALL.into_iter().chain(generic) {
+        let (stream, url, rest_url) = match exchange {
+            Exchange::Bitstamp => (args.bitstamp_stream, &args.bitstamp_url, &args.bitstamp_rest_url),
+            Exchange::Binance => (args.binance_stream, &args.binance_url, &args.binance_rest_url),
+            Exchange::Kraken => (None, &args.kraken_url, &args.kraken_rest_url),
+            Exchange::Coinbase => (None, &args.coinbase_url, &args.coinbase_rest_url),
+            Exchange::Bitfinex => (None, &args.bitfinex_url, &args.bitfinex_rest_url),
+            Exchange::Generic(_) => (None, &None, &None),
+        };
+        let stale_after = stale_after.iter()
+            .rfind(|(e, _)| *e == exchange)
+            .map(|(_, timeout)| timeout.as_secs_f64());
+
+        let e = config.exchange(exchange.clone());
+        if !enabled.is_empty() {
+            e.enabled = Some(enabled.contains(&exchange));
+        }
+        if let Some(stream) = stream {
+            e.stream = stream;
+        }
+        if let Some(url) = url {
+            e.url = Some(url.clone());
+        }
+        if let Some(rest_url) = rest_url {
+            e.rest_url = Some(rest_url.clone());
+        }
+        if exchange == Exchange::Bitfinex && args.bitfinex_precision.is_some() {
+            e.precision = args.bitfinex_precision;
+        }
+        if stale_after.is_some() {
+            e.stale_after = stale_after;
+        }
+    }
+    Ok(())
+}
+
+/// An exchange by its name, either one with an adapter of its own or a venue of
+/// the config.
+fn exchange(config: &Config, name: &str) -> Result<Exchange, String> {
+    name.parse().or_else(|e| {
+        config.exchanges.keys()
+            .find(|exchange| exchange.to_string() == name)
+            .cloned()
+            .ok_or_else(|| format!("{}, a venue of its own needs an [exchanges.{}] table in the config", e, name))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_after_must_be_a_positive_duration() {
+        assert_eq!(parse_stale_after("binance=2.5"), Ok(("binance".to_string(), Duration::from_millis(2500))));
+        assert_eq!(parse_stale_after("binance=0"), Err("0: must be positive".to_string()));
+        assert_eq!(parse_stale_after("binance=-1"), Err("-1: must be positive".to_string()));
+        assert!(parse_stale_after("binance=NaN").is_err());
+        assert!(parse_stale_after("binance=inf").is_err());
+        assert!(parse_stale_after("=5").is_err());
+        assert!(parse_stale_after("binance").is_err());
+    }
+}
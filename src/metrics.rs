@@ -0,0 +1,123 @@
+use crate::error::Error;
+use crate::orderbook::Exchange;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use lazy_static::lazy_static;
+use log::info;
+use prometheus::{
+    Encoder, HistogramVec, IntCounterVec, IntGauge, GaugeVec, TextEncoder,
+    register_gauge_vec, register_histogram_vec, register_int_counter_vec, register_int_gauge,
+};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+lazy_static! {
+    pub(crate) static ref MESSAGES: IntCounterVec = register_int_counter_vec!(
+        "ordermaster_messages_total",
+        "Websocket messages received from an exchange",
+        &["exchange"]
+    ).unwrap();
+
+    pub(crate) static ref PARSE_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "ordermaster_parse_errors_total",
+        "Messages of an exchange that failed to parse",
+        &["exchange"]
+    ).unwrap();
+
+    pub(crate) static ref RECONNECTS: IntCounterVec = register_int_counter_vec!(
+        "ordermaster_reconnects_total",
+        "Times the feed of an exchange dropped and reconnected",
+        &["exchange"]
+    ).unwrap();
+
+    pub(crate) static ref SPREAD: GaugeVec = register_gauge_vec!(
+        "ordermaster_spread",
+        "Current spread of the merged book of a symbol",
+        &["symbol"]
+    ).unwrap();
+
+    pub(crate) static ref BEST_BID: GaugeVec = register_gauge_vec!(
+        "ordermaster_best_bid",
+        "Current best bid of a symbol on an exchange",
+        &["symbol", "exchange"]
+    ).unwrap();
+
+    pub(crate) static ref BEST_ASK: GaugeVec = register_gauge_vec!(
+        "ordermaster_best_ask",
+        "Current best ask of a symbol on an exchange",
+        &["symbol", "exchange"]
+    ).unwrap();
+
+    pub(crate) static ref GRPC_CLIENTS: IntGauge = register_int_gauge!(
+        "ordermaster_grpc_clients",
+        "gRPC clients currently streaming from the server"
+    ).unwrap();
+
+    pub(crate) static ref BOOK_UPDATES: IntCounterVec = register_int_counter_vec!(
+        "ordermaster_book_updates_total",
+        "Merged books of a symbol published to the gRPC subscribers",
+        &["symbol"]
+    ).unwrap();
+
+    pub(crate) static ref AGGREGATION_LATENCY: HistogramVec = register_histogram_vec!(
+        "ordermaster_aggregation_latency_seconds",
+        "Time from receiving a tick of an exchange to publishing the merged book",
+        &["exchange"],
+        vec![0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1]
+    ).unwrap();
+}
+
+/// Counts a message of an exchange that failed to parse.
+pub(crate) fn parse_error(exchange: Exchange) {
+    PARSE_ERRORS.with_label_values(&[&exchange.to_string()]).inc();
+}
+
+/// Counts a gRPC client for as long as it's kept.
+pub(crate) struct ClientGuard;
+
+impl ClientGuard {
+    pub(crate) fn new() -> ClientGuard {
+        GRPC_CLIENTS.inc();
+        ClientGuard
+    }
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        GRPC_CLIENTS.dec();
+    }
+}
+
+/// Serves the metrics in the Prometheus text format at `/metrics`.
+pub(crate) async fn serve(addr: SocketAddr) -> Result<(), Error> {
+    info!("Serving metrics at {}", addr);
+
+    let make_service = make_service_fn(|_conn| async {
+        Ok::<_, Infallible>(service_fn(handle))
+    });
+
+    Server::bind(&addr)
+        .serve(make_service)
+        .await?;
+
+    Ok(())
+}
+
+async fn handle(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => {
+            let encoder = TextEncoder::new();
+            let mut buffer = vec![];
+            encoder.encode(&prometheus::gather(), &mut buffer).expect("metrics should encode");
+
+            Response::builder()
+                .header(hyper::header::CONTENT_TYPE, encoder.format_type())
+                .body(Body::from(buffer))
+        },
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    };
+
+    Ok(response.expect("response should build"))
+}
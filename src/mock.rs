@@ -0,0 +1,735 @@
+use crate::orderbook::Exchange;
+use futures::{SinkExt, StreamExt};
+use log::{info, warn};
+use rand::Rng;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ArgEnum)]
+pub enum Scenario {
+    /// Subscriptions succeed and order books stream forever.
+    Ok,
+
+    /// Bitstamp answers subscriptions with `bts:error`, Kraken with an error status,
+    /// Coinbase and Bitfinex with an error, and Binance stays silent as it does for
+    /// pairs it doesn't list.
+    SubscriptionError,
+
+    /// The connection drops without a Close-handshake.
+    AbruptClose,
+
+    /// A message that isn't valid JSON is sent.
+    Malformed,
+
+    /// Order books are sent as fast as possible, to see how a slow consumer copes.
+    SlowConsumer,
+
+    /// Bitstamp sends `bts:request_reconnect` as it does ahead of maintenance, and
+    /// keeps streaming until the client hangs up.
+    RequestReconnect,
+
+    /// Kraken sends a wrong checksum once.
+    ChecksumMismatch,
+
+    /// Coinbase stops sending heartbeats, and the first Bitfinex channel stops
+    /// sending anything.
+    MissingHeartbeat,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Script {
+    pub scenario: Scenario,
+
+    /// Time between order book messages.
+    pub interval: Duration,
+
+    /// Order book messages sent before the scenario kicks in.
+    pub after: usize,
+}
+
+impl Script {
+    /// Waits until the next order book message is due.
+    async fn tick(&self) {
+        if self.scenario != Scenario::SlowConsumer {
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+
+    /// Whether the scenario kicks in before the `sent`th order book message.
+    fn due(&self, sent: usize) -> bool {
+        sent >= self.after
+    }
+}
+
+/// Serves the stand-in of an exchange on a bound listener, e.g. one on an
+/// ephemeral port. Any `Generic` venue gets the made-up one.
+pub async fn serve(listener: TcpListener, exchange: Exchange, script: Script) {
+    match exchange {
+        Exchange::Binance => accept(listener, script, binance).await,
+        Exchange::Bitstamp => accept(listener, script, bitstamp).await,
+        Exchange::Kraken => accept(listener, script, kraken).await,
+        Exchange::Coinbase => accept(listener, script, coinbase).await,
+        Exchange::Bitfinex => accept(listener, script, bitfinex).await,
+        Exchange::Generic(_) => accept(listener, script, generic).await,
+    }
+}
+
+async fn accept<F, Fut>(listener: TcpListener, script: Script, serve: F)
+    where F: Fn(TcpStream, Script) -> Fut,
+          Fut: std::future::Future<Output = ()> + Send + 'static,
+{
+    match listener.local_addr() {
+        Ok(addr) => info!("Listening on ws://{} playing {:?}", addr, script.scenario),
+        Err(e) => warn!("Listening on an unknown address: {:?}", e),
+    }
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                info!("Connection from {}", addr);
+                tokio::spawn(serve(stream, script));
+            },
+            Err(e) => warn!("Failed to accept: {:?}", e),
+        }
+    }
+}
+
+/// Streams the partial depth, or the book ticker, of every stream named in the
+/// url, e.g. `/stream?streams=ethbtc@depth20@100ms/ltcbtc@bookTicker`.
+async fn binance(stream: TcpStream, script: Script) {
+    let mut path = String::new();
+    // the error response is tungstenite's to choose
+    #[allow(clippy::result_large_err)]
+    let callback = |req: &Request, res: Response| {
+        path = req.uri().to_string();
+        Ok(res)
+    };
+    let mut ws_stream = match tokio_tungstenite::accept_hdr_async(stream, callback).await {
+        Ok(ws_stream) => ws_stream,
+        Err(e) => return warn!("Handshake failed: {:?}", e),
+    };
+
+    let streams: Vec<String> = path.split_once("streams=")
+        .map(|(_, streams)| streams.split('/').map(String::from).collect())
+        .unwrap_or_default();
+    info!("Binance streams {:?}", streams);
+
+    if script.scenario == Scenario::SubscriptionError {
+        // Binance doesn't complain about pairs it doesn't list, it just sends nothing
+        while let Some(Ok(_)) = ws_stream.next().await {}
+        return
+    }
+
+    let mut book = Book::new();
+    for sent in 0.. {
+        if misbehave(&mut ws_stream, script, sent).await {
+            return
+        }
+        script.tick().await;
+
+        for stream in &streams {
+            book.step();
+            let (bids, asks) = (book.levels(-1), book.levels(1));
+            let data = match stream.ends_with("@bookTicker") {
+                true => json!({
+                    "u": book.update_id,
+                    "s": stream.split('@').next().unwrap_or_default().to_uppercase(),
+                    "b": bids[0][0],
+                    "B": bids[0][1],
+                    "a": asks[0][0],
+                    "A": asks[0][1],
+                }),
+                false => json!({ "lastUpdateId": book.update_id, "bids": bids, "asks": asks }),
+            };
+            let msg = json!({ "stream": stream, "data": data });
+            if ws_stream.send(Message::Text(msg.to_string())).await.is_err() {
+                return
+            }
+        }
+    }
+}
+
+/// Answers `bts:subscribe` requests, then streams the order book of every channel
+/// subscribed to.
+async fn bitstamp(stream: TcpStream, script: Script) {
+    let mut ws_stream = match tokio_tungstenite::accept_async(stream).await {
+        Ok(ws_stream) => ws_stream,
+        Err(e) => return warn!("Handshake failed: {:?}", e),
+    };
+
+    let mut channels: HashSet<String> = HashSet::new();
+    let mut book = Book::new();
+    let mut sent = 0;
+
+    loop {
+        tokio::select! {
+            msg = ws_stream.next() => {
+                let msg = match msg {
+                    Some(Ok(Message::Text(msg))) => msg,
+                    Some(Ok(Message::Close(_))) | None | Some(Err(_)) => return,
+                    Some(Ok(_)) => continue,
+                };
+                let msg: Value = match serde_json::from_str(&msg) {
+                    Ok(msg) => msg,
+                    Err(_) => continue,
+                };
+                if msg["event"] != "bts:subscribe" {
+                    continue
+                }
+
+                let channel = msg["data"]["channel"].as_str().unwrap_or_default().to_string();
+                let reply = match script.scenario {
+                    Scenario::SubscriptionError => json!({
+                        "event": "bts:error",
+                        "channel": "",
+                        "data": { "code": null, "message": format!("Bad subscription string {}.", channel) },
+                    }),
+                    _ => {
+                        channels.insert(channel.clone());
+                        json!({ "event": "bts:subscription_succeeded", "channel": channel, "data": {} })
+                    },
+                };
+                if ws_stream.send(Message::Text(reply.to_string())).await.is_err() {
+                    return
+                }
+            },
+            _ = script.tick(), if !channels.is_empty() => {
+                if misbehave(&mut ws_stream, script, sent).await {
+                    return
+                }
+                if script.scenario == Scenario::RequestReconnect && sent == script.after {
+                    info!("Requesting a reconnect");
+                    let msg = json!({ "event": "bts:request_reconnect", "channel": "", "data": "" });
+                    if ws_stream.send(Message::Text(msg.to_string())).await.is_err() {
+                        return
+                    }
+                }
+                sent += 1;
+
+                for channel in &channels {
+                    book.step();
+                    let now = chrono::Utc::now();
+                    let msg = json!({
+                        "event": "data",
+                        "channel": channel,
+                        "data": {
+                            "timestamp": now.timestamp().to_string(),
+                            "microtimestamp": now.timestamp_micros().to_string(),
+                            "bids": book.levels(-1),
+                            "asks": book.levels(1),
+                        },
+                    });
+                    if ws_stream.send(Message::Text(msg.to_string())).await.is_err() {
+                        return
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// Answers book subscriptions with a snapshot of every pair, then streams updates
+/// replacing all of its levels, each with the checksum of the book. Spread
+/// subscriptions get the best bid and ask instead.
+async fn kraken(stream: TcpStream, script: Script) {
+    let mut ws_stream = match tokio_tungstenite::accept_async(stream).await {
+        Ok(ws_stream) => ws_stream,
+        Err(e) => return warn!("Handshake failed: {:?}", e),
+    };
+    let status = json!({ "event": "systemStatus", "status": "online", "version": "1.9.0" });
+    if ws_stream.send(Message::Text(status.to_string())).await.is_err() {
+        return
+    }
+
+    // the levels last sent of every pair
+    let mut pairs: Vec<(String, Levels, Levels)> = vec![];
+    let mut spreads: Vec<String> = vec![];
+    let mut book = Book::new();
+    let mut sent = 0;
+
+    loop {
+        tokio::select! {
+            msg = ws_stream.next() => {
+                let msg = match msg {
+                    Some(Ok(Message::Text(msg))) => msg,
+                    Some(Ok(Message::Close(_))) | None | Some(Err(_)) => return,
+                    Some(Ok(_)) => continue,
+                };
+                let msg: Value = match serde_json::from_str(&msg) {
+                    Ok(msg) => msg,
+                    Err(_) => continue,
+                };
+                if msg["event"] != "subscribe" {
+                    continue
+                }
+
+                let requested: Vec<String> = msg["pair"].as_array().into_iter().flatten()
+                    .filter_map(|p| p.as_str().map(String::from))
+                    .collect();
+                for pair in requested {
+                    let mut replies = vec![];
+                    match script.scenario {
+                        Scenario::SubscriptionError => replies.push(json!({
+                            "event": "subscriptionStatus",
+                            "pair": pair,
+                            "status": "error",
+                            "errorMessage": "Currency pair not supported",
+                        })),
+                        _ if msg["subscription"]["name"] == "spread" => {
+                            replies.push(json!({ "event": "subscriptionStatus", "pair": pair, "status": "subscribed" }));
+                            spreads.push(pair);
+                        },
+                        _ => {
+                            book.step();
+                            let (bids, asks) = (book.levels(-1), book.levels(1));
+                            replies.push(json!({ "event": "subscriptionStatus", "pair": pair, "status": "subscribed" }));
+                            replies.push(json!([1, { "bs": kraken_levels(&bids), "as": kraken_levels(&asks) }, "book-100", pair]));
+                            pairs.push((pair, bids, asks));
+                        },
+                    }
+                    for reply in replies {
+                        if ws_stream.send(Message::Text(reply.to_string())).await.is_err() {
+                            return
+                        }
+                    }
+                }
+            },
+            _ = script.tick(), if !pairs.is_empty() || !spreads.is_empty() => {
+                if misbehave(&mut ws_stream, script, sent).await {
+                    return
+                }
+                let corrupt = script.scenario == Scenario::ChecksumMismatch && sent == script.after;
+                sent += 1;
+
+                for pair in &spreads {
+                    book.step();
+                    let (bids, asks) = (book.levels(-1), book.levels(1));
+                    let [bid, ask] = [&bids[0], &asks[0]];
+                    let now = chrono::Utc::now();
+                    let timestamp = format!("{}.{:06}", now.timestamp(), now.timestamp_subsec_micros());
+                    let msg = json!([2, [bid[0], ask[0], timestamp, bid[1], ask[1]], "spread", pair]);
+                    if ws_stream.send(Message::Text(msg.to_string())).await.is_err() {
+                        return
+                    }
+                }
+
+                for (pair, bids, asks) in pairs.iter_mut() {
+                    book.step();
+                    let (new_bids, new_asks) = (book.levels(-1), book.levels(1));
+                    let mut checksum = kraken_checksum(&new_bids, &new_asks);
+                    if corrupt {
+                        info!("Sending a wrong checksum");
+                        checksum = checksum.wrapping_add(1);
+                    }
+
+                    let msg = json!([
+                        1,
+                        { "a": kraken_update(asks, &new_asks) },
+                        { "b": kraken_update(bids, &new_bids), "c": checksum.to_string() },
+                        "book-100",
+                        pair,
+                    ]);
+                    *bids = new_bids;
+                    *asks = new_asks;
+                    if ws_stream.send(Message::Text(msg.to_string())).await.is_err() {
+                        return
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// Answers subscriptions with the products subscribed to and a snapshot of each,
+/// then streams `l2update`s replacing all of its levels, and a heartbeat per
+/// product every second.
+async fn coinbase(stream: TcpStream, script: Script) {
+    let mut ws_stream = match tokio_tungstenite::accept_async(stream).await {
+        Ok(ws_stream) => ws_stream,
+        Err(e) => return warn!("Handshake failed: {:?}", e),
+    };
+
+    // the levels last sent of every product
+    let mut products: Vec<(String, Levels, Levels)> = vec![];
+    let mut book = Book::new();
+    let mut sent = 0;
+    let mut heartbeat = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        let mut replies = vec![];
+        tokio::select! {
+            msg = ws_stream.next() => {
+                let msg = match msg {
+                    Some(Ok(Message::Text(msg))) => msg,
+                    Some(Ok(Message::Close(_))) | None | Some(Err(_)) => return,
+                    Some(Ok(_)) => continue,
+                };
+                let msg: Value = match serde_json::from_str(&msg) {
+                    Ok(msg) => msg,
+                    Err(_) => continue,
+                };
+                if msg["type"] != "subscribe" {
+                    continue
+                }
+
+                let requested: Vec<String> = msg["product_ids"].as_array().into_iter().flatten()
+                    .filter_map(|p| p.as_str().map(String::from))
+                    .collect();
+                if script.scenario == Scenario::SubscriptionError {
+                    replies.push(json!({
+                        "type": "error",
+                        "message": "Failed to subscribe",
+                        "reason": format!("{} is not a valid product", requested.join(", ")),
+                    }));
+                } else {
+                    let channels: Vec<Value> = msg["channels"].as_array().into_iter().flatten()
+                        .map(|name| json!({ "name": name, "product_ids": requested }))
+                        .collect();
+                    replies.push(json!({ "type": "subscriptions", "channels": channels }));
+                    for product_id in requested {
+                        book.step();
+                        let (bids, asks) = (book.levels(-1), book.levels(1));
+                        replies.push(json!({ "type": "snapshot", "product_id": product_id, "bids": bids, "asks": asks }));
+                        products.push((product_id, bids, asks));
+                    }
+                }
+            },
+            _ = script.tick(), if !products.is_empty() => {
+                if misbehave(&mut ws_stream, script, sent).await {
+                    return
+                }
+                sent += 1;
+
+                for (product_id, bids, asks) in products.iter_mut() {
+                    book.step();
+                    let (new_bids, new_asks) = (book.levels(-1), book.levels(1));
+                    let changes: Vec<[String; 3]> = coinbase_changes("buy", bids, &new_bids).into_iter()
+                        .chain(coinbase_changes("sell", asks, &new_asks))
+                        .collect();
+                    replies.push(json!({
+                        "type": "l2update",
+                        "product_id": product_id,
+                        "time": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
+                        "changes": changes,
+                    }));
+                    *bids = new_bids;
+                    *asks = new_asks;
+                }
+            },
+            _ = heartbeat.tick(), if !products.is_empty() => {
+                if script.scenario == Scenario::MissingHeartbeat && script.due(sent) {
+                    continue
+                }
+                for (product_id, _, _) in &products {
+                    replies.push(json!({
+                        "type": "heartbeat",
+                        "product_id": product_id,
+                        "sequence": book.update_id,
+                        "last_trade_id": 0,
+                        "time": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
+                    }));
+                }
+            },
+        }
+
+        for reply in replies {
+            if ws_stream.send(Message::Text(reply.to_string())).await.is_err() {
+                return
+            }
+        }
+    }
+}
+
+/// Streams the book channel of every symbol subscribed to, each on its own
+/// channel id, with a heartbeat every 15 seconds.
+async fn bitfinex(stream: TcpStream, script: Script) {
+    let mut ws_stream = match tokio_tungstenite::accept_async(stream).await {
+        Ok(ws_stream) => ws_stream,
+        Err(e) => return warn!("Handshake failed: {:?}", e),
+    };
+    let info = json!({ "event": "info", "version": 2, "serverId": "mock", "platform": { "status": 1 } });
+    if ws_stream.send(Message::Text(info.to_string())).await.is_err() {
+        return
+    }
+
+    // the levels last sent on every channel
+    let mut channels: Vec<(u64, Levels, Levels)> = vec![];
+    let mut book = Book::new();
+    // levels per side subscribed to
+    let mut len = 10;
+    let mut sent = 0;
+    let mut heartbeat = tokio::time::interval(Duration::from_secs(15));
+
+    loop {
+        let mut replies = vec![];
+        tokio::select! {
+            msg = ws_stream.next() => {
+                let msg = match msg {
+                    Some(Ok(Message::Text(msg))) => msg,
+                    Some(Ok(Message::Close(_))) | None | Some(Err(_)) => return,
+                    Some(Ok(_)) => continue,
+                };
+                let msg: Value = match serde_json::from_str(&msg) {
+                    Ok(msg) => msg,
+                    Err(_) => continue,
+                };
+                if msg["event"] != "subscribe" {
+                    continue
+                }
+
+                let symbol = msg["symbol"].as_str().unwrap_or_default();
+                if script.scenario == Scenario::SubscriptionError {
+                    replies.push(json!({
+                        "event": "error",
+                        "msg": "symbol: invalid",
+                        "code": 10300,
+                        "channel": "book",
+                        "symbol": symbol,
+                    }));
+                } else {
+                    let chan_id = 17000 + channels.len() as u64;
+                    len = msg["len"].as_str().and_then(|l| l.parse().ok()).unwrap_or(len);
+                    replies.push(json!({
+                        "event": "subscribed",
+                        "channel": "book",
+                        "chanId": chan_id,
+                        "symbol": symbol,
+                        "pair": symbol.trim_start_matches('t'),
+                        "prec": msg["prec"],
+                        "freq": "F0",
+                        "len": msg["len"],
+                    }));
+                    book.step();
+                    let (bids, asks) = (book.top(-1, len), book.top(1, len));
+                    let entries: Vec<Value> = bitfinex_entries(&bids, 1.0).into_iter()
+                        .chain(bitfinex_entries(&asks, -1.0))
+                        .collect();
+                    replies.push(json!([chan_id, entries]));
+                    channels.push((chan_id, bids, asks));
+                }
+            },
+            _ = script.tick(), if !channels.is_empty() => {
+                if misbehave(&mut ws_stream, script, sent).await {
+                    return
+                }
+                sent += 1;
+
+                for (i, (chan_id, bids, asks)) in channels.iter_mut().enumerate() {
+                    book.step();
+                    let (new_bids, new_asks) = (book.top(-1, len), book.top(1, len));
+                    if i == 0 && script.scenario == Scenario::MissingHeartbeat && script.due(sent) {
+                        continue
+                    }
+                    let entries = bitfinex_update(bids, &new_bids, 1.0).into_iter()
+                        .chain(bitfinex_update(asks, &new_asks, -1.0));
+                    replies.extend(entries.map(|entry| json!([chan_id, entry])));
+                    *bids = new_bids;
+                    *asks = new_asks;
+                }
+            },
+            _ = heartbeat.tick(), if !channels.is_empty() => {
+                for (i, (chan_id, _, _)) in channels.iter().enumerate() {
+                    if i == 0 && script.scenario == Scenario::MissingHeartbeat && script.due(sent) {
+                        continue
+                    }
+                    replies.push(json!([chan_id, "hb"]));
+                }
+            },
+        }
+
+        for reply in replies {
+            if ws_stream.send(Message::Text(reply.to_string())).await.is_err() {
+                return
+            }
+        }
+    }
+}
+
+/// Streams the top levels of every symbol subscribed to with `{"op": "subscribe",
+/// "channel": "book", "symbol": "eth_btc"}`, as objects of price and size, with
+/// a ping every second. Pairs it doesn't list don't exist for it.
+async fn generic(stream: TcpStream, script: Script) {
+    let mut ws_stream = match tokio_tungstenite::accept_async(stream).await {
+        Ok(ws_stream) => ws_stream,
+        Err(e) => return warn!("Handshake failed: {:?}", e),
+    };
+
+    let mut symbols: Vec<String> = vec![];
+    let mut book = Book::new();
+    let mut sent = 0;
+    let mut ping = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        let mut replies = vec![];
+        tokio::select! {
+            msg = ws_stream.next() => {
+                let msg = match msg {
+                    Some(Ok(Message::Text(msg))) => msg,
+                    Some(Ok(Message::Close(_))) | None | Some(Err(_)) => return,
+                    Some(Ok(_)) => continue,
+                };
+                let msg: Value = match serde_json::from_str(&msg) {
+                    Ok(msg) => msg,
+                    Err(_) => continue,
+                };
+                if msg["op"] == "subscribe" && script.scenario != Scenario::SubscriptionError {
+                    let symbol = msg["symbol"].as_str().unwrap_or_default().to_string();
+                    replies.push(json!({ "type": "subscribed", "symbol": symbol }));
+                    symbols.push(symbol);
+                }
+            },
+            _ = script.tick(), if !symbols.is_empty() => {
+                if misbehave(&mut ws_stream, script, sent).await {
+                    return
+                }
+                sent += 1;
+
+                for symbol in &symbols {
+                    book.step();
+                    let side = |levels: Levels| -> Vec<Value> {
+                        levels.into_iter().map(|[price, size]| json!({ "price": price, "size": size })).collect()
+                    };
+                    replies.push(json!({
+                        "type": "book",
+                        "symbol": symbol,
+                        "data": { "bids": side(book.levels(-1)), "asks": side(book.levels(1)) },
+                    }));
+                }
+            },
+            _ = ping.tick() => replies.push(json!({ "type": "ping" })),
+        }
+
+        for reply in replies {
+            if ws_stream.send(Message::Text(reply.to_string())).await.is_err() {
+                return
+            }
+        }
+    }
+}
+
+/// Levels as Bitfinex's `[price, count, amount]`, amounts signed by `side`: 1
+/// for bids and -1 for asks.
+fn bitfinex_entries(levels: &[[String; 2]], side: f64) -> Vec<Value> {
+    levels.iter()
+        .map(|[price, amount]| {
+            let amount: f64 = amount.parse().unwrap_or_default();
+            json!([price.parse::<f64>().unwrap_or_default(), 1, side * amount])
+        })
+        .collect()
+}
+
+/// Deletes the old levels the new ones don't replace with a count of 0, then
+/// sets the new ones.
+fn bitfinex_update(old: &[[String; 2]], new: &[[String; 2]], side: f64) -> Vec<Value> {
+    let deleted = old.iter()
+        .filter(|[price, _]| !new.iter().any(|[p, _]| p == price))
+        .map(|[price, _]| json!([price.parse::<f64>().unwrap_or_default(), 0, side]));
+    deleted.chain(bitfinex_entries(new, side)).collect()
+}
+
+/// Removes the old levels the new ones don't replace, then sets the new ones.
+fn coinbase_changes(side: &str, old: &[[String; 2]], new: &[[String; 2]]) -> Vec<[String; 3]> {
+    let removed = old.iter()
+        .filter(|[price, _]| !new.iter().any(|[p, _]| p == price))
+        .map(|[price, _]| [side.to_string(), price.clone(), "0".to_string()]);
+    let set = new.iter()
+        .map(|[price, size]| [side.to_string(), price.clone(), size.clone()]);
+    removed.chain(set).collect()
+}
+
+/// Levels as Kraken's `[price, volume, timestamp]`.
+fn kraken_levels(levels: &[[String; 2]]) -> Vec<[String; 3]> {
+    let now = chrono::Utc::now();
+    let timestamp = format!("{}.{:06}", now.timestamp(), now.timestamp_subsec_micros());
+    levels.iter()
+        .map(|[price, amount]| [price.clone(), amount.clone(), timestamp.clone()])
+        .collect()
+}
+
+/// Deletes the old levels the new ones don't replace, then sets the new ones.
+fn kraken_update(old: &[[String; 2]], new: &[[String; 2]]) -> Vec<[String; 3]> {
+    let deleted: Vec<[String; 2]> = old.iter()
+        .filter(|[price, _]| !new.iter().any(|[p, _]| p == price))
+        .map(|[price, _]| [price.clone(), "0.00000000".to_string()])
+        .collect();
+    kraken_levels(&deleted).into_iter().chain(kraken_levels(new)).collect()
+}
+
+/// CRC32 of the asks, then the bids, best first, without decimal points and
+/// leading zeros.
+fn kraken_checksum(bids: &[[String; 2]], asks: &[[String; 2]]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    for [price, amount] in asks.iter().chain(bids) {
+        for s in [price, amount] {
+            hasher.update(s.replace('.', "").trim_start_matches('0').as_bytes());
+        }
+    }
+    hasher.finalize()
+}
+
+/// Plays the failure of the scenario once it's due. Returns whether the
+/// connection is done for.
+async fn misbehave(ws_stream: &mut WebSocketStream<TcpStream>, script: Script, sent: usize) -> bool {
+    if !script.due(sent) {
+        return false
+    }
+
+    match script.scenario {
+        Scenario::AbruptClose => {
+            info!("Dropping the connection");
+            let _ = ws_stream.get_mut().shutdown().await;
+            true
+        },
+        Scenario::Malformed if sent == script.after => {
+            info!("Sending malformed JSON");
+            ws_stream.send(Message::Text("{\"event\": \"data\", \"chan".to_string())).await.is_err()
+        },
+        _ => false,
+    }
+}
+
+/// Levels as `[price, amount]` pairs, best first.
+type Levels = Vec<[String; 2]>;
+
+/// An order book walking randomly around a mid price.
+struct Book {
+    mid: f64,
+    update_id: u64,
+}
+
+impl Book {
+    fn new() -> Book {
+        Book { mid: 0.07, update_id: 1 }
+    }
+
+    fn step(&mut self) {
+        self.mid *= 1.0 + rand::thread_rng().gen_range(-0.0005..0.0005);
+        self.update_id += 1;
+    }
+
+    /// The best `n` of the levels.
+    fn top(&self, direction: i32, n: usize) -> Levels {
+        let mut levels = self.levels(direction);
+        levels.truncate(n);
+        levels
+    }
+
+    /// Ten levels as `[price, amount]` pairs, bids below the mid for a `direction`
+    /// of -1 and asks above it for 1.
+    fn levels(&self, direction: i32) -> Levels {
+        let mut rng = rand::thread_rng();
+        (1..=10)
+            .map(|i| {
+                let price = self.mid * (1.0 + f64::from(direction * i) * 0.0001);
+                let amount: f64 = rng.gen_range(0.1..10.0);
+                [format!("{:.8}", price), format!("{:.8}", amount)]
+            })
+            .collect()
+    }
+}
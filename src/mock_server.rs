@@ -0,0 +1,65 @@
+use clap::Parser;
+use keyrock_orders::mock::{self, Scenario, Script};
+use keyrock_orders::Exchange;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+/// Stands in for the exchanges, serving Binance partial depth streams, Bitstamp
+/// order book channels, Kraken books, Coinbase level2 channels, Bitfinex book
+/// channels and the books of a made-up venue on local websockets.
+#[derive(Parser)]
+struct Cli {
+    #[clap(long, help = "(Optional) Port of the Binance stand-in, point the server at ws://127.0.0.1:<PORT>. Default: 9443")]
+    binance_port: Option<u16>,
+
+    #[clap(long, help = "(Optional) Port of the Bitstamp stand-in, point the server at ws://127.0.0.1:<PORT>. Default: 9444")]
+    bitstamp_port: Option<u16>,
+
+    #[clap(long, help = "(Optional) Port of the Kraken stand-in, point the server at ws://127.0.0.1:<PORT>. Default: 9445")]
+    kraken_port: Option<u16>,
+
+    #[clap(long, help = "(Optional) Port of the Coinbase stand-in, point the server at ws://127.0.0.1:<PORT>. Default: 9446")]
+    coinbase_port: Option<u16>,
+
+    #[clap(long, help = "(Optional) Port of the Bitfinex stand-in, point the server at ws://127.0.0.1:<PORT>. Default: 9447")]
+    bitfinex_port: Option<u16>,
+
+    #[clap(long, help = "(Optional) Port of the stand-in for a venue configured by its JSON format, as in the README. Default: 9448")]
+    generic_port: Option<u16>,
+
+    #[clap(long, arg_enum, help = "(Optional) How the stand-ins behave. Default: ok")]
+    scenario: Option<Scenario>,
+
+    #[clap(long, help = "(Optional) Milliseconds between order book messages. Default: 100")]
+    interval: Option<u64>,
+
+    #[clap(long, help = "(Optional) Order book messages sent before the scenario kicks in. Default: 10")]
+    after: Option<usize>,
+}
+
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let args = Cli::parse();
+
+    let script = Script {
+        scenario: args.scenario.unwrap_or(Scenario::Ok),
+        interval: Duration::from_millis(args.interval.unwrap_or(100)),
+        after: args.after.unwrap_or(10),
+    };
+
+    let binance = listen(args.binance_port.unwrap_or(9443), Exchange::Binance, script);
+    let bitstamp = listen(args.bitstamp_port.unwrap_or(9444), Exchange::Bitstamp, script);
+    let kraken = listen(args.kraken_port.unwrap_or(9445), Exchange::Kraken, script);
+    let coinbase = listen(args.coinbase_port.unwrap_or(9446), Exchange::Coinbase, script);
+    let bitfinex = listen(args.bitfinex_port.unwrap_or(9447), Exchange::Bitfinex, script);
+    let generic = listen(args.generic_port.unwrap_or(9448), Exchange::Generic("acme".to_string()), script);
+
+    let _ = tokio::join!(binance, bitstamp, kraken, coinbase, bitfinex, generic);
+}
+
+async fn listen(port: u16, exchange: Exchange, script: Script) {
+    let listener = TcpListener::bind(("127.0.0.1", port)).await.expect("Failed to bind");
+    mock::serve(listener, exchange, script).await
+}
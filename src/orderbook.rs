@@ -1,18 +1,45 @@
+use chrono::{DateTime, Utc};
 use std::cmp::Ordering;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
+use serde::Deserialize;
 use crate::DEPTH;
 
 #[derive(Debug, PartialEq)]
 pub(crate) struct InTick {
     pub(crate) exchange: Exchange,
+    pub(crate) symbol: String,
     pub(crate) bids: Vec<Level>,
     pub(crate) asks: Vec<Level>,
+    pub(crate) time: TickTime,
+}
+
+/// When and in which order an exchange sent a tick.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TickTime {
+    /// Event time reported by the exchange, if it reports one.
+    pub(crate) exchange: Option<DateTime<Utc>>,
+
+    /// When the message carrying the tick arrived.
+    pub(crate) received: DateTime<Utc>,
+
+    /// Update id of the exchange's book, if it numbers them.
+    pub(crate) sequence: Option<u64>,
+}
+
+impl TickTime {
+    /// Stamps a tick as received now.
+    pub(crate) fn new(exchange: Option<DateTime<Utc>>, sequence: Option<u64>) -> TickTime {
+        TickTime { exchange, received: Utc::now(), sequence }
+    }
 }
 
 pub(crate) trait ToTick {
-    fn maybe_to_tick(&self) -> Option<InTick>;
+    fn maybe_to_tick(&self, symbol: &str) -> Option<InTick>;
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -20,29 +47,81 @@ pub(crate) struct OutTick {
     pub(crate) spread: Decimal,
     pub(crate) bids: Vec<Level>,
     pub(crate) asks: Vec<Level>,
+    pub(crate) sequence: u64,
+    pub(crate) timestamp: DateTime<Utc>,
+
+    /// Exchanges left out because their orderbook went stale.
+    pub(crate) excluded: Vec<Exchange>,
+
+    /// Time of the last tick of every exchange in the view, stale ones included.
+    pub(crate) venues: BTreeMap<Exchange, TickTime>,
 }
 
 impl OutTick {
-    pub(crate) fn new() -> OutTick {
-        OutTick {
-            spread: Default::default(),
-            bids: vec![],
-            asks: vec![],
-        }
+    /// Whether both ticks show the same levels, regardless of when they were made.
+    pub(crate) fn same_levels(&self, other: &OutTick) -> bool {
+        self.spread == other.spread && self.bids == other.bids && self.asks == other.asks
+            && self.excluded == other.excluded
     }
 }
 
-#[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq)]
-pub(crate) enum Exchange {
+#[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Exchange {
     Bitstamp,
     Binance,
+    Kraken,
+    Coinbase,
+    Bitfinex,
+
+    /// A venue configured with a `JsonFormat`, by the name of its `[exchanges.<name>]`
+    /// table. Only the config names one, parsing knows just those with an adapter.
+    Generic(String),
 }
 
-impl ToString for Exchange {
-    fn to_string(&self) -> String {
+impl Exchange {
+    /// The exchanges with an adapter of their own.
+    pub const ALL: [Exchange; 5] = [
+        Exchange::Bitstamp,
+        Exchange::Binance,
+        Exchange::Kraken,
+        Exchange::Coinbase,
+        Exchange::Bitfinex,
+    ];
+}
+
+impl FromStr for Exchange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bitstamp" => Ok(Exchange::Bitstamp),
+            "binance" => Ok(Exchange::Binance),
+            "kraken" => Ok(Exchange::Kraken),
+            "coinbase" => Ok(Exchange::Coinbase),
+            "bitfinex" => Ok(Exchange::Bitfinex),
+            _ => Err(format!("unknown exchange {}", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for Exchange {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Exchange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Exchange::Bitstamp => "bitstamp".to_string(),
-            Exchange::Binance => "binance".to_string(),
+            Exchange::Bitstamp => write!(f, "bitstamp"),
+            Exchange::Binance => write!(f, "binance"),
+            Exchange::Kraken => write!(f, "kraken"),
+            Exchange::Coinbase => write!(f, "coinbase"),
+            Exchange::Bitfinex => write!(f, "bitfinex"),
+            Exchange::Generic(name) => write!(f, "{}", name),
         }
     }
 }
@@ -73,11 +152,7 @@ impl Ord for Level {
 
 impl PartialOrd for Level {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        match (self.price.partial_cmp(&other.price), &self.side) {
-            (Some(Ordering::Equal), Side::Bid) => self.amount.partial_cmp(&other.amount),
-            (Some(Ordering::Equal), Side::Ask) => self.amount.partial_cmp(&other.amount).map(Ordering::reverse),
-            (ord, _) => ord,
-        }
+        Some(self.cmp(other))
     }
 }
 
@@ -100,7 +175,7 @@ impl<T> ToLevels for Vec<T>
 {
     fn to_levels(&self, side: Side, depth: usize) -> Vec<Level> {
         let levels = match self.len() > depth {
-            true => self.split_at(depth).0.to_vec(), // only keep 10
+            true => self.split_at(depth).0.to_vec(),
             false => self.clone(),
         };
 
@@ -112,7 +187,6 @@ impl<T> ToLevels for Vec<T>
 
 trait Merge {
     fn merge(self, other: Vec<Level>) -> Vec<Level>;
-    fn merge_map(self, other: LevelsMap) -> Vec<Level>;
 }
 
 impl Merge for Vec<Level> {
@@ -124,55 +198,127 @@ impl Merge for Vec<Level> {
         levels.sort_unstable();
         levels
     }
+}
+
+/// What a subscriber wants to see of the merged orderbook.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct BookView {
+    pub(crate) depth: usize,
 
-    fn merge_map(self, other: LevelsMap) -> Vec<Level> {
-        let levels: Vec<Level> = other.values().cloned().collect();
-        self.merge(levels)
+    /// Exchanges to merge, all of them when empty.
+    pub(crate) exchanges: Vec<Exchange>,
+
+    /// Side to return, both when `None`.
+    pub(crate) side: Option<Side>,
+}
+
+impl Default for BookView {
+    fn default() -> Self {
+        BookView {
+            depth: DEPTH,
+            exchanges: vec![],
+            side: None,
+        }
     }
 }
 
-#[derive(Debug, PartialEq)]
+/// How long an exchange may go without updating its orderbook before it's left out
+/// of the merged book. Exchanges without a timeout are never left out.
+pub type StaleAfter = BTreeMap<Exchange, Duration>;
+
+#[derive(Debug, Clone, PartialEq)]
 pub(crate) struct Exchanges {
-    bitstamp: OrderDepths,
-    binance: OrderDepths,
+    books: BTreeMap<Exchange, OrderDepths>,
+
+    stale_after: StaleAfter,
+
+    /// Exchanges whose orderbook outlived its staleness timeout.
+    stale: BTreeSet<Exchange>,
+
+    /// Increases with every change of the orderbooks.
+    sequence: u64,
+
+    /// When the orderbooks last changed.
+    timestamp: DateTime<Utc>,
 }
 
 impl Exchanges {
-    pub(crate) fn new() -> Exchanges {
+    pub(crate) fn new(stale_after: StaleAfter) -> Exchanges {
         Exchanges {
-            bitstamp: OrderDepths::new(),
-            binance: OrderDepths::new(),
+            books: BTreeMap::new(),
+            stale_after,
+            stale: BTreeSet::new(),
+            sequence: 0,
+            timestamp: Utc::now(),
         }
     }
 
     /// Extracts the bids and asks from the `InTick`, then adds into its corresponding
     /// orderbook of the exchange.
     pub(crate) fn update(&mut self, t: InTick) {
-        match t.exchange {
-            Exchange::Bitstamp => {
-                self.bitstamp.bids = t.bids;
-                self.bitstamp.asks = t.asks;
-            },
-            Exchange::Binance => {
-                self.binance.bids = t.bids;
-                self.binance.asks = t.asks;
-            },
+        self.stale.remove(&t.exchange);
+        self.books.insert(t.exchange, OrderDepths { bids: t.bids, asks: t.asks, time: t.time });
+        self.touch();
+    }
 
+    /// Drops the orderbook of an exchange, e.g. while its feed is reconnecting.
+    pub(crate) fn remove(&mut self, exchange: &Exchange) {
+        self.stale.remove(exchange);
+        if self.books.remove(exchange).is_some() {
+            self.touch();
         }
     }
 
-    /// Returns a new `OutTick` containing the merge bids and asks from both orderbooks.
-    pub(crate) fn to_tick(&self) -> OutTick {
-        let bids: Vec<Level> =
-            self.bitstamp.bids.clone()
-                .merge(self.binance.bids.clone())
-                .into_iter().rev().take(DEPTH)
+    /// Recomputes which exchanges haven't updated their orderbook within their
+    /// staleness timeout. Returns whether that set changed.
+    pub(crate) fn refresh_stale(&mut self) -> bool {
+        let now = Utc::now();
+        let stale: BTreeSet<Exchange> = self.books.iter()
+            .filter(|(e, book)| {
+                let age = (now - book.time.received).to_std().unwrap_or_default();
+                self.stale_after.get(e).is_some_and(|timeout| age > *timeout)
+            })
+            .map(|(e, _)| e.clone())
+            .collect();
+
+        let changed = stale != self.stale;
+        if changed {
+            self.stale = stale;
+            self.touch();
+        }
+        changed
+    }
+
+    fn touch(&mut self) {
+        self.sequence += 1;
+        self.timestamp = Utc::now();
+    }
+
+    /// Returns a new `OutTick` containing the merged bids and asks from the orderbooks
+    /// of the exchanges in the view. The spread is always computed from both sides.
+    pub(crate) fn to_tick(&self, view: &BookView) -> OutTick {
+        let in_view: Vec<(&Exchange, &OrderDepths)> = self.books.iter()
+            .filter(|(e, _)| view.exchanges.is_empty() || view.exchanges.contains(e))
+            .collect();
+        let venues = in_view.iter()
+            .map(|(e, book)| ((*e).clone(), book.time.clone()))
+            .collect();
+
+        let (excluded, books): (Vec<_>, Vec<_>) = in_view.into_iter()
+            .partition(|(e, _)| self.stale.contains(e));
+        let excluded: Vec<Exchange> = excluded.into_iter().map(|(e, _)| e.clone()).collect();
+        let books: Vec<&OrderDepths> = books.into_iter().map(|(_, book)| book).collect();
+
+        let mut bids: Vec<Level> =
+            books.iter()
+                .fold(vec![], |levels, book| levels.merge(book.bids.clone()))
+                .into_iter().rev().take(view.depth)
                 .collect();
 
-        let asks: Vec<Level> =
-            self.bitstamp.asks.clone()
-                .merge(self.binance.asks.clone())
-                .into_iter().take(DEPTH)
+        let mut asks: Vec<Level> =
+            books.iter()
+                .fold(vec![], |levels, book| levels.merge(book.asks.clone()))
+                .into_iter().take(view.depth)
                 .collect();
 
         let spread = match (bids.first(), asks.first()) {
@@ -180,50 +326,257 @@ impl Exchanges {
             (_, _) => dec!(0),
         };
 
-        OutTick { spread, bids, asks }
+        match view.side {
+            Some(Side::Bid) => asks.clear(),
+            Some(Side::Ask) => bids.clear(),
+            None => {},
+        }
+
+        OutTick {
+            spread,
+            bids,
+            asks,
+            sequence: self.sequence,
+            timestamp: self.timestamp,
+            excluded,
+            venues,
+        }
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 struct OrderDepths {
     bids: Vec<Level>,
     asks: Vec<Level>,
+    time: TickTime,
 }
 
-impl OrderDepths {
-    fn new() -> Self {
-        OrderDepths {
-            bids: vec![],
-            asks: vec![],
-        }
-    }
-}
+pub(crate) type LevelsMap = BTreeMap<Decimal, Level>;
 
-type LevelsMap = BTreeMap<Decimal, Level>;
+/// Deepest a local book kept from diff updates may grow on each side.
+const BOOK_DEPTH: usize = 5000;
 
+/// A full local orderbook of one exchange, maintained from snapshots and diff updates.
 #[derive(Debug, PartialEq)]
-struct OrderDepthsMap {
+pub(crate) struct OrderDepthsMap {
     bids: LevelsMap,
     asks: LevelsMap,
+    depth: usize,
 }
 
+impl OrderDepthsMap {
+    pub(crate) fn new() -> Self {
+        Self::with_depth(BOOK_DEPTH)
+    }
+
+    /// A book keeping only the best `depth` levels of each side, for exchanges
+    /// which expect the levels past the depth subscribed to be dropped.
+    pub(crate) fn with_depth(depth: usize) -> Self {
+        OrderDepthsMap {
+            bids: LevelsMap::new(),
+            asks: LevelsMap::new(),
+            depth,
+        }
+    }
+
+    /// Replaces the whole book, e.g. with a fresh snapshot.
+    pub(crate) fn reset(&mut self, bids: Vec<Level>, asks: Vec<Level>) {
+        self.bids.clear();
+        self.asks.clear();
+        self.extend(bids, asks);
+    }
+
+    /// Applies diff updates to the book, levels with a zero amount are removed.
+    pub(crate) fn extend(&mut self, bids: Vec<Level>, asks: Vec<Level>) {
+        self.bids.extend_and_keep(bids.to_map(), Side::Bid, self.depth);
+        self.asks.extend_and_keep(asks.to_map(), Side::Ask, self.depth);
+    }
+
+    /// The best `n` levels of a side, best first.
+    pub(crate) fn best(&self, side: Side, n: usize) -> Vec<&Level> {
+        match side {
+            Side::Bid => self.bids.values().rev().take(n).collect(),
+            Side::Ask => self.asks.values().take(n).collect(),
+        }
+    }
+
+    /// Returns an `InTick` with the best `depth` levels of each side.
+    pub(crate) fn to_tick(
+        &self,
+        exchange: Exchange,
+        symbol: &str,
+        depth: usize,
+        time: TickTime,
+    ) -> InTick
+    {
+        let bids = self.bids.values().rev().take(depth).cloned().collect();
+        let asks = self.asks.values().take(depth).cloned().collect();
+
+        InTick { exchange, symbol: symbol.to_string(), bids, asks, time }
+    }
+}
+
+trait ToMap {
+    fn to_map(self) -> LevelsMap;
+}
+
+impl ToMap for Vec<Level> {
+    fn to_map(self) -> LevelsMap {
+        self.into_iter()
+            .map(|l| (l.price, l))
+            .collect()
+    }
+}
 
 trait ExtendAndKeep {
     fn extend_and_keep(
         &mut self,
         other: LevelsMap,
+        side: Side,
         index: usize,
     );
 }
 
 impl ExtendAndKeep for LevelsMap {
-    fn extend_and_keep(&mut self, other: LevelsMap, i: usize) {
+    /// Extends the levels and keeps only the best `i` of them for the given side.
+    fn extend_and_keep(&mut self, other: LevelsMap, side: Side, i: usize) {
         self.extend(other);
         self.retain(|_k, v| !v.amount.eq(&dec!(0))); // remove where volume is 0
-        if self.len() > i {
-            let key = self.keys().collect::<Vec<&Decimal>>()[i].clone();
-            self.split_off(&key);
+        if i == 0 {
+            self.clear();
+        } else if self.len() > i {
+            match side {
+                Side::Bid => {
+                    let key = *self.keys().nth_back(i - 1).unwrap();
+                    *self = self.split_off(&key);
+                },
+                Side::Ask => {
+                    let key = *self.keys().nth(i).unwrap();
+                    self.split_off(&key);
+                },
+            }
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn levels(side: Side, prices: &[i64]) -> LevelsMap {
+        prices.iter()
+            .map(|p| Level::new(side.clone(), Decimal::from(*p), dec!(1), Exchange::Binance))
+            .collect::<Vec<Level>>()
+            .to_map()
+    }
+
+    fn prices(levels: &LevelsMap) -> Vec<Decimal> {
+        levels.keys().cloned().collect()
+    }
+
+    #[test]
+    fn extend_and_keep_trims_the_lowest_bids() {
+        let mut bids = levels(Side::Bid, &[1, 2, 3]);
+        bids.extend_and_keep(levels(Side::Bid, &[4, 5]), Side::Bid, 3);
+
+        assert_eq!(prices(&bids), vec![dec!(3), dec!(4), dec!(5)]);
+    }
+
+    #[test]
+    fn extend_and_keep_trims_the_highest_asks() {
+        let mut asks = levels(Side::Ask, &[3, 4, 5]);
+        asks.extend_and_keep(levels(Side::Ask, &[1, 2]), Side::Ask, 3);
+
+        assert_eq!(prices(&asks), vec![dec!(1), dec!(2), dec!(3)]);
+    }
+
+    #[test]
+    fn extend_and_keep_removes_zero_amounts_before_trimming() {
+        let mut bids = levels(Side::Bid, &[1, 2, 3]);
+        let mut removed = levels(Side::Bid, &[3]);
+        removed.values_mut().for_each(|l| l.amount = dec!(0));
+        bids.extend_and_keep(removed, Side::Bid, 2);
+
+        assert_eq!(prices(&bids), vec![dec!(1), dec!(2)]);
+    }
+
+    #[test]
+    fn extend_and_keep_keeps_nothing_at_a_depth_of_0() {
+        let mut bids = levels(Side::Bid, &[1, 2]);
+        bids.extend_and_keep(levels(Side::Bid, &[3]), Side::Bid, 0);
+        let mut asks = levels(Side::Ask, &[1, 2]);
+        asks.extend_and_keep(levels(Side::Ask, &[3]), Side::Ask, 0);
+
+        assert!(bids.is_empty());
+        assert!(asks.is_empty());
+    }
+
+    #[test]
+    fn order_depths_map_keeps_its_depth() {
+        let mut book = OrderDepthsMap::with_depth(2);
+        book.reset(
+            levels(Side::Bid, &[1, 2, 3]).into_values().collect(),
+            levels(Side::Ask, &[4, 5, 6]).into_values().collect(),
+        );
+
+        let best = |side| book.best(side, 10).iter().map(|l| l.price).collect::<Vec<_>>();
+        assert_eq!(best(Side::Bid), vec![dec!(3), dec!(2)]);
+        assert_eq!(best(Side::Ask), vec![dec!(4), dec!(5)]);
+    }
+
+    fn tick(exchange: Exchange, bid: i64, ask: i64, age: chrono::Duration) -> InTick {
+        let mut time = TickTime::new(None, None);
+        time.received -= age;
+        InTick {
+            exchange: exchange.clone(),
+            symbol: "ETH/BTC".to_string(),
+            bids: vec![Level::new(Side::Bid, Decimal::from(bid), dec!(1), exchange.clone())],
+            asks: vec![Level::new(Side::Ask, Decimal::from(ask), dec!(1), exchange)],
+            time,
+        }
+    }
+
+    #[test]
+    fn stale_exchanges_are_left_out_until_they_update() {
+        let stale_after = StaleAfter::from([(Exchange::Binance, Duration::from_secs(5))]);
+        let mut exchanges = Exchanges::new(stale_after);
+        exchanges.update(tick(Exchange::Binance, 3, 4, chrono::Duration::seconds(10)));
+        // no timeout, never left out however old
+        exchanges.update(tick(Exchange::Bitstamp, 2, 5, chrono::Duration::seconds(60)));
+
+        assert!(exchanges.refresh_stale());
+        assert!(!exchanges.refresh_stale());
+        let out = exchanges.to_tick(&BookView::default());
+        assert_eq!(out.excluded, vec![Exchange::Binance]);
+        assert_eq!(out.bids.iter().map(|l| l.price).collect::<Vec<_>>(), vec![dec!(2)]);
+        assert_eq!(out.asks.iter().map(|l| l.price).collect::<Vec<_>>(), vec![dec!(5)]);
+        // still reported among the venues
+        assert!(out.venues.contains_key(&Exchange::Binance));
+
+        exchanges.update(tick(Exchange::Binance, 3, 4, chrono::Duration::zero()));
+        assert!(!exchanges.refresh_stale());
+        let out = exchanges.to_tick(&BookView::default());
+        assert!(out.excluded.is_empty());
+        assert_eq!(out.bids[0].price, dec!(3));
+    }
+
+    #[test]
+    fn fresh_exchanges_are_kept() {
+        let stale_after = StaleAfter::from([(Exchange::Binance, Duration::from_secs(5))]);
+        let mut exchanges = Exchanges::new(stale_after);
+        exchanges.update(tick(Exchange::Binance, 3, 4, chrono::Duration::seconds(1)));
+
+        assert!(!exchanges.refresh_stale());
+        assert!(exchanges.to_tick(&BookView::default()).excluded.is_empty());
+    }
+
+    #[test]
+    fn exchange_names_parse_strictly() {
+        assert_eq!("binance".parse::<Exchange>(), Ok(Exchange::Binance));
+        assert_eq!("bitfinex".parse::<Exchange>(), Ok(Exchange::Bitfinex));
+        assert!("binanse".parse::<Exchange>().is_err());
+        assert!("Binance".parse::<Exchange>().is_err());
+        // a venue of its own is only named by the config
+        assert!("acme".parse::<Exchange>().is_err());
+    }
+}
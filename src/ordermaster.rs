@@ -1,108 +1,276 @@
+use chrono::Utc;
+use crate::adapter::ExchangeAdapter;
+use crate::backoff::Backoff;
+use crate::bitfinex::Precision;
 use crate::error::{Error, ExchangeErr};
+use crate::generic::{self, JsonFormat};
 use crate::grpc::OrderBookService;
-use crate::orderbook::{Exchanges, InTick, OutTick};
-use crate::{bitstamp, binance, websocket};
+use crate::instrument::Instrument;
+use crate::latency::Latency;
+use crate::metrics;
+use crate::orderbook::{BookView, Exchange, Exchanges, InTick, StaleAfter};
+use crate::record::Recorder;
+use crate::replay::Replay;
+use crate::status::Status;
+use crate::websocket::WsStream;
+use crate::{bitfinex, bitstamp, binance, coinbase, kraken, BookStream};
 use futures::channel::mpsc::UnboundedSender;
-use futures::{join, StreamExt};
-use log::{debug, error, info};
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use log::{debug, error, info, warn};
+use rust_decimal::prelude::ToPrimitive;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::{RwLock, watch};
 use tungstenite::protocol::Message;
 
-pub async fn run(
-    symbol: &String,
-    port: usize,
-) -> Result<(), Error>
-{
-    let connector = Connector::new();
-    let service = OrderBookService::new(connector.out_ticks.clone());
+/// What the server aggregates and how.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub symbols: Vec<Instrument>,
+
+    /// Levels per side of the book when a subscriber doesn't ask for a depth.
+    pub depth: usize,
+
+    pub grpc_addr: SocketAddr,
+
+    /// Address serving the Prometheus metrics, none if not served.
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// The exchanges to aggregate.
+    pub exchanges: BTreeMap<Exchange, ExchangeSettings>,
+
+    /// Aggregates just the best bid and ask of every exchange, streamed from its
+    /// fastest top of book feed where it has one.
+    pub bbo: bool,
+
+    /// Directory to record the raw websocket traffic to, none if not recorded.
+    pub record: Option<PathBuf>,
+
+    /// Recorded traffic to stream from instead of the exchanges.
+    pub replay: Option<Replay>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ExchangeSettings {
+    /// Websocket endpoint, e.g. `BITSTAMP_WS_URL`.
+    pub url: String,
+
+    /// REST endpoint of the listings and snapshots, e.g. `BITSTAMP_REST_URL`, none
+    /// for a `Generic` exchange.
+    pub rest_url: Option<String>,
+
+    pub stream: BookStream,
+
+    /// Time without an update before the exchange is left out of the book.
+    pub stale_after: Option<Duration>,
+
+    /// Precision of the book of Bitfinex, `P0` if not set.
+    pub precision: Option<Precision>,
 
+    /// How a `Generic` exchange sends its books.
+    pub json: Option<JsonFormat>,
+}
+
+pub async fn run(settings: Settings) -> Result<(), Error> {
+    let stale_after: StaleAfter = settings.exchanges.iter()
+        .filter_map(|(exchange, s)| s.stale_after.map(|timeout| (exchange.clone(), timeout)))
+        .collect();
+
+    let connector = Connector::new(&settings.symbols, &stale_after, settings.bbo);
+    let service = OrderBookService::new(
+        connector.books.clone(),
+        connector.status.clone(),
+        connector.latency.clone(),
+        settings.depth,
+        settings.exchanges.keys().cloned().collect(),
+    );
+
+    let grpc_addr = settings.grpc_addr;
     tokio::spawn(async move {
-        service.serve(port).await.expect("Failed to serve grpc");
+        service.serve(grpc_addr).await.expect("Failed to serve grpc");
     });
 
-    connector.run(symbol).await?;
+    if let Some(addr) = settings.metrics_addr {
+        tokio::spawn(async move {
+            metrics::serve(addr).await.expect("Failed to serve metrics");
+        });
+    }
+
+    let mut adapters: Vec<Box<dyn ExchangeAdapter>> = settings.exchanges.iter()
+        .map(|(exchange, s)| -> Result<Box<dyn ExchangeAdapter>, Error> {
+            let rest_url = || s.rest_url.as_deref()
+                .ok_or_else(|| Error::BadConfig(format!("{}: no rest_url", exchange)));
+            Ok(match exchange {
+                Exchange::Bitstamp => Box::new(bitstamp::Adapter::new(&settings.symbols, s.stream, &s.url, rest_url()?)),
+                Exchange::Binance => Box::new(binance::Adapter::new(&settings.symbols, s.stream, settings.bbo, &s.url, rest_url()?)),
+                Exchange::Kraken => Box::new(kraken::Adapter::new(&settings.symbols, settings.bbo, &s.url, rest_url()?)),
+                Exchange::Coinbase => Box::new(coinbase::Adapter::new(&settings.symbols, &s.url, rest_url()?)),
+                Exchange::Bitfinex => Box::new(bitfinex::Adapter::new(&settings.symbols, settings.bbo, s.precision.unwrap_or_default(), &s.url, rest_url()?)),
+                Exchange::Generic(_) => {
+                    let format = s.json.clone()
+                        .ok_or_else(|| Error::BadConfig(format!("{}: no json format", exchange)))?;
+                    Box::new(generic::Adapter::new(exchange.clone(), &settings.symbols, &s.url, format))
+                },
+            })
+        })
+        .collect::<Result<_, _>>()?;
+
+    // a recording streams whatever was listed back then
+    if settings.replay.is_none() {
+        for adapter in adapters.iter_mut() {
+            adapter.resolve().await?;
+        }
+    }
+
+    let traffic = Traffic { record: settings.record, replay: settings.replay };
+    connector.run(adapters, traffic).await?;
 
     Ok(())
 }
 
-pub(crate) type OutTickPair = (watch::Sender<OutTick>, watch::Receiver<OutTick>);
+/// Shares the orderbooks of a symbol with the subscribers, which each compute
+/// their own view of it.
+pub(crate) type ExchangesPair = (watch::Sender<Exchanges>, watch::Receiver<Exchanges>);
+
+/// The `ExchangesPair` of every symbol, keyed by symbol.
+pub(crate) type Books = HashMap<String, ExchangesPair>;
+
+pub(crate) type StatusPair = (watch::Sender<Status>, watch::Receiver<Status>);
+
+pub(crate) type LatencyPair = (watch::Sender<Latency>, watch::Receiver<Latency>);
+
+/// How often the message rates of the feeds are sampled and the status and
+/// latencies published.
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the latencies are logged.
+const LATENCY_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long an exchange has to confirm the subscriptions of a new connection.
+const SUBSCRIBE_TIMEOUT: Duration = Duration::from_secs(15);
 
 struct Connector {
-    out_ticks: Arc<RwLock<OutTickPair>>,
+    books: Arc<RwLock<Books>>,
+    status: Arc<RwLock<StatusPair>>,
+    latency: Arc<RwLock<LatencyPair>>,
+    stale_after: StaleAfter,
+
+    /// Keeps just the best bid and ask of every tick.
+    bbo: bool,
 }
 
 impl Connector {
-    fn new() -> Connector {
-        let out_ticks = Arc::new(RwLock::new(watch::channel(OutTick::new())));
-        Connector { out_ticks }
+    fn new(symbols: &[Instrument], stale_after: &StaleAfter, bbo: bool) -> Connector {
+        let books = symbols.iter()
+            .map(|symbol| (symbol.to_string(), watch::channel(Exchanges::new(stale_after.clone()))))
+            .collect();
+        let status = watch::channel(Status::new());
+        let latency = watch::channel(Latency::new());
+        Connector {
+            books: Arc::new(RwLock::new(books)),
+            status: Arc::new(RwLock::new(status)),
+            latency: Arc::new(RwLock::new(latency)),
+            stale_after: stale_after.clone(),
+            bbo,
+        }
     }
 
     async fn run(
         &self,
-        symbol: &String,
+        adapters: Vec<Box<dyn ExchangeAdapter>>,
+        traffic: Traffic,
      ) -> Result<(), Error>
     {
-        let (
-            ws_bitstamp,
-            ws_binance,
-        ) = join!(
-            bitstamp::connect(symbol),
-            binance::connect(symbol),
-        );
-        let mut ws_bitstamp = ws_bitstamp?;
-        let mut ws_binance = ws_binance?;
-
-        //let rx_stdin = stdin::rx();
-        let (tx_in_ticks, mut rx_in_ticks) = futures::channel::mpsc::unbounded();
-
-        let mut exchanges = Exchanges::new();
-
-        // handle websocket messages
+        let (tx_feed, mut rx_feed) = futures::channel::mpsc::unbounded();
+
+        // each exchange streams from its own task and reconnects on its own
+        let mut feeds: FuturesUnordered<_> = adapters.into_iter()
+            .map(|adapter| tokio::spawn(feed(adapter, tx_feed.clone(), traffic.clone())))
+            .collect();
+
+        let mut books: HashMap<String, Exchanges> = self.books.read().await
+            .keys()
+            .map(|symbol| (symbol.clone(), Exchanges::new(self.stale_after.clone())))
+            .collect();
+
+        let mut status = Status::new();
+        let mut status_interval = tokio::time::interval(STATUS_INTERVAL);
+        let mut sampled_at = Instant::now();
+
+        let mut latency = Latency::new();
+        let mut latency_log_interval = tokio::time::interval(LATENCY_LOG_INTERVAL);
+
+        // handle feed events
         loop {
             tokio::select! {
 
-                ws_msg = ws_bitstamp.next() => {
-                    let tx = tx_in_ticks.clone();
-
-                    let res = handle(ws_msg)
-                        .and_then(|msg| {
-                             msg.parse_and_send(bitstamp::parse, tx)
-                        })
-                        .map_err(ExchangeErr::Bitstamp);
+                res = feeds.next() => {
+                    match res {
+                        Some(Err(e)) => error!("Feed task failed: {:?}", e),
+                        Some(Ok(Err(e))) => return Err(e),
+                        Some(Ok(Ok(()))) => {},
+                        None => break,
+                    }
+                },
+                _ = status_interval.tick() => {
+                    status.sample(sampled_at.elapsed());
+                    sampled_at = Instant::now();
+                    self.publish_status(&status).await;
+                    self.publish_latency(&latency).await;
 
-                    if let Err(e) = res {
-                        error!("Err: {:?}", e);
-                        break
+                    for (symbol, exchanges) in books.iter_mut() {
+                        if exchanges.refresh_stale() {
+                            warn!("{} stale exchanges: {:?}", symbol, exchanges.to_tick(&BookView::default()).excluded);
+                            self.publish(symbol, exchanges).await;
+                        }
                     }
                 },
-                ws_msg = ws_binance.next() => {
-                    let tx = tx_in_ticks.clone();
-
-                    let res = handle(ws_msg)
-                        .and_then(|msg| {
-                            msg.parse_and_send(binance::parse, tx)
-                        })
-                        .map_err(ExchangeErr::Binance);
-
-                    if let Err(e) = res {
-                        error!("Err: {:?}", e);
-                        break
+                _ = latency_log_interval.tick() => {
+                    for (exchange, venue) in &latency.venues {
+                        info!("{} latency exchange->receive {}", exchange, venue.exchange_to_receive);
+                        info!("{} latency receive->publish {}", exchange, venue.receive_to_publish);
                     }
                 },
-                in_tick = rx_in_ticks.next() => {
-                    match in_tick {
-                        Some(t) => {
-                            debug!("{:?}", t);
-                            exchanges.update(t);
+                Some(event) = rx_feed.next() => {
+                    if status.update(&event) {
+                        self.publish_status(&status).await;
+                    }
 
-                            let out_tick = exchanges.to_tick();
-                            debug!("{:?}", out_tick);
+                    match event {
+                        FeedEvent::Tick(mut t) => {
+                            debug!("{:?}", t);
+                            if self.bbo {
+                                t.bids.truncate(1);
+                                t.asks.truncate(1);
+                            }
+                            if let Some(exchanges) = books.get_mut(&t.symbol) {
+                                let symbol = t.symbol.clone();
+                                let (exchange, time) = (t.exchange.clone(), t.time.clone());
+                                record_best(&t);
+                                exchanges.update(t);
+                                self.publish(&symbol, exchanges).await;
 
-                            let writer = self.out_ticks.write().await;
-                            let tx = &writer.0;
+                                let published = Utc::now();
+                                latency.record(&exchange, time.exchange, time.received, published);
+                                metrics::AGGREGATION_LATENCY
+                                    .with_label_values(&[&exchange.to_string()])
+                                    .observe((published - time.received).to_std().unwrap_or_default().as_secs_f64());
+                            }
+                        },
+                        FeedEvent::Down(e) => {
+                            for (symbol, exchanges) in books.iter_mut() {
+                                let labels = [symbol.as_str(), &e.exchange.to_string()];
+                                let _ = metrics::BEST_BID.remove_label_values(&labels);
+                                let _ = metrics::BEST_ASK.remove_label_values(&labels);
 
-                            tx.send(out_tick).expect("channel should not be closed");
+                                exchanges.remove(&e.exchange);
+                                self.publish(symbol, exchanges).await;
+                            }
                         },
                         _ => {},
                     }
@@ -110,13 +278,229 @@ impl Connector {
             };
         }
 
+        Ok(())
+    }
+
+    /// Sends the orderbooks of a symbol to its subscribers.
+    async fn publish(&self, symbol: &str, exchanges: &Exchanges) {
+        let out_tick = exchanges.to_tick(&BookView::default());
+        debug!("{} {:?}", symbol, out_tick);
+
+        metrics::SPREAD.with_label_values(&[symbol]).set(out_tick.spread.to_f64().unwrap_or_default());
+        metrics::BOOK_UPDATES.with_label_values(&[symbol]).inc();
+
+        let writer = self.books.write().await;
+        if let Some((tx, _)) = writer.get(symbol) {
+            tx.send(exchanges.clone()).expect("channel should not be closed");
+        }
+    }
+
+    /// Sends the health of the feeds to its subscribers.
+    async fn publish_status(&self, status: &Status) {
+        let writer = self.status.write().await;
+        writer.0.send(status.clone()).expect("channel should not be closed");
+    }
+
+    /// Sends the latencies of the exchanges to its subscribers.
+    async fn publish_latency(&self, latency: &Latency) {
+        let writer = self.latency.write().await;
+        writer.0.send(latency.clone()).expect("channel should not be closed");
+    }
+}
+
+/// Sets the best bid and ask of the exchange of the tick.
+fn record_best(t: &InTick) {
+    let labels = [t.symbol.as_str(), &t.exchange.to_string()];
+    if let Some(bid) = t.bids.first() {
+        metrics::BEST_BID.with_label_values(&labels).set(bid.price.to_f64().unwrap_or_default());
+    }
+    if let Some(ask) = t.asks.first() {
+        metrics::BEST_ASK.with_label_values(&labels).set(ask.price.to_f64().unwrap_or_default());
+    }
+}
+
+/// What a feed reports back to the `Connector`.
+#[derive(Debug)]
+pub(crate) enum FeedEvent {
+    Connecting(Exchange),
+
+    /// The exchange is subscribed and about to stream.
+    Connected(Exchange),
+
+    /// A websocket message arrived, whether it carries a tick or not.
+    Received(Exchange),
+
+    Tick(InTick),
+
+    /// The connection dropped, the exchange's levels are stale until it's back.
+    Down(ExchangeErr),
+}
+
+/// Where the websocket traffic of the exchanges comes from and goes to.
+#[derive(Debug, Clone)]
+struct Traffic {
+    record: Option<PathBuf>,
+    replay: Option<Replay>,
+}
+
+/// Streams the `InTick`s of one exchange forever, reconnecting with backoff
+/// whenever the connection fails. Gives up if the exchange doesn't take the
+/// subscriptions of the first connection, as they won't do any better later.
+async fn feed(
+    mut adapter: Box<dyn ExchangeAdapter>,
+    tx: UnboundedSender<FeedEvent>,
+    traffic: Traffic,
+) -> Result<(), Error>
+{
+    let exchange = adapter.exchange();
+    let mut backoff = Backoff::new();
+    let reconnects = metrics::RECONNECTS.with_label_values(&[&exchange.to_string()]);
+    let mut subscribed = false;
+
+    // a replay is opened once, a retry carries on after the message that failed
+    let mut replay = traffic.replay.as_ref().map(|replay| replay.open(&exchange));
+
+    loop {
+        if tx.unbounded_send(FeedEvent::Connecting(exchange.clone())).is_err() {
+            break
+        }
+
+        let e = match stream(adapter.as_mut(), &tx, &mut backoff, &traffic, &mut replay, &mut subscribed).await {
+            Err(e) => e,
+            Ok(never) => match never {},
+        };
+        if !subscribed && matches!(e, Error::Rejected { .. } | Error::Unconfirmed { .. }) {
+            return Err(e)
+        }
+        let e = ExchangeErr::new(exchange.clone(), e);
+        error!("Err: {}", e);
+
+        if tx.unbounded_send(FeedEvent::Down(e)).is_err() {
+            break
+        }
+        reconnects.inc();
+
+        let delay = backoff.next_delay();
+        info!("Reconnecting to {} in {:?}", exchange, delay);
+        tokio::time::sleep(delay).await;
+    }
+    Ok(())
+}
+
+/// Connects, or takes over the replay, and subscribes, then sends `InTick`s until
+/// the connection fails. Every connection is recorded to its own files if asked to.
+/// A replay is handed back when failing rather than closed, so it isn't restarted.
+///
+/// The exchange has `SUBSCRIBE_TIMEOUT` to confirm the subscriptions, `subscribed`
+/// is set once it did. A replay plays back whatever was confirmed when recorded.
+///
+/// When the adapter has a reconnect due, e.g. ahead of maintenance, a new
+/// connection is subscribed before the old one is closed. The exchange isn't
+/// reported down meanwhile, so its levels stay in the book until new ones arrive.
+async fn stream(
+    adapter: &mut dyn ExchangeAdapter,
+    tx: &UnboundedSender<FeedEvent>,
+    backoff: &mut Backoff,
+    traffic: &Traffic,
+    replay: &mut Option<WsStream>,
+    subscribed: &mut bool,
+) -> Result<Infallible, Error>
+{
+    let exchange = adapter.exchange();
+    let messages = metrics::MESSAGES.with_label_values(&[&exchange.to_string()]);
+
+    let mut ws_stream = match replay.take() {
+        Some(ws_stream) => ws_stream,
+        None => adapter.connect().await?,
+    };
+    adapter.subscribe(&mut ws_stream).await?;
+
+    let mut confirm_by = tokio::time::Instant::now() + SUBSCRIBE_TIMEOUT;
+    let mut confirming = traffic.replay.is_none();
+    if !confirming {
+        let _ = tx.unbounded_send(FeedEvent::Connected(exchange.clone()));
+    }
+
+    let mut recorder = start_recording(traffic, &exchange).await?;
+
+    let res = loop {
+        // a replay going quiet is just over
+        let idle_timeout = adapter.idle_timeout().filter(|_| traffic.replay.is_none());
+        let idle_by = idle_timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+        let next = match [confirming.then_some(confirm_by), idle_by].into_iter().flatten().min() {
+            Some(deadline) => match tokio::time::timeout_at(deadline, ws_stream.next()).await {
+                Ok(next) => next,
+                Err(_) if confirming && deadline == confirm_by => {
+                    break Err(Error::Unconfirmed { exchange: exchange.clone(), instruments: adapter.unconfirmed() })
+                },
+                Err(_) => break Err(Error::Silent(idle_timeout.unwrap_or_default())),
+            },
+            None => ws_stream.next().await,
+        };
+        let msg = match handle(next) {
+            Ok(msg) => msg,
+            Err(e) => break Err(e),
+        };
+        messages.inc();
+        let _ = tx.unbounded_send(FeedEvent::Received(exchange.clone()));
+
+        // record before parsing, so the input of a failing parse is kept
+        if let Some(r) = recorder.as_mut() {
+            if let Err(e) = r.write(&msg).await {
+                error!("Stopped recording {}: {:?}", exchange, e);
+                recorder = None;
+            }
+        }
+
+        match msg.parse_and_send(adapter, tx) {
+            // only consider the connection healthy once data flows again
+            Ok(true) => backoff.reset(),
+            Ok(false) => {},
+            Err(e) => break Err(e),
+        }
+
+        if confirming && adapter.unconfirmed().is_empty() {
+            info!("{} confirmed the subscriptions", exchange);
+            confirming = false;
+            *subscribed = true;
+            let _ = tx.unbounded_send(FeedEvent::Connected(exchange.clone()));
+        }
+
+        if traffic.replay.is_none() && adapter.reconnect_due() {
+            info!("Reconnecting to {} as planned", exchange);
+            let mut fresh = match adapter.connect().await {
+                Ok(fresh) => fresh,
+                Err(e) => break Err(e),
+            };
+            if let Err(e) = adapter.subscribe(&mut fresh).await {
+                break Err(e)
+            }
+            adapter.close(&mut ws_stream).await;
+            ws_stream = fresh;
+
+            confirm_by = tokio::time::Instant::now() + SUBSCRIBE_TIMEOUT;
+            confirming = true;
+            recorder = match start_recording(traffic, &exchange).await {
+                Ok(recorder) => recorder,
+                Err(e) => break Err(e),
+            };
+        }
+    };
+
+    match traffic.replay {
+        Some(_) => *replay = Some(ws_stream),
         // Gracefully close connection by Close-handshake procedure
-        join!(
-            websocket::close(&mut ws_bitstamp),
-            websocket::close(&mut ws_binance),
-        );
+        None => adapter.close(&mut ws_stream).await,
+    }
 
-        Ok(())
+    res
+}
+
+/// Starts recording a new connection if asked to.
+async fn start_recording(traffic: &Traffic, exchange: &Exchange) -> Result<Option<Recorder>, Error> {
+    match &traffic.record {
+        Some(dir) => Ok(Some(Recorder::open(dir, exchange).await?)),
+        None => Ok(None),
     }
 }
 
@@ -124,36 +508,37 @@ fn handle(
     ws_msg: Option<Result<Message, tungstenite::Error>>,
 ) -> Result<Message, Error>
 {
-    let msg = ws_msg.unwrap_or_else(|| {
-        info!("no message");
-        Err(tungstenite::Error::ConnectionClosed)
-    })?;
-
-    Ok(msg)
+    match ws_msg {
+        Some(msg) => Ok(msg?),
+        None => {
+            info!("no message");
+            Err(tungstenite::Error::ConnectionClosed.into())
+        },
+    }
 }
 
 trait ParseAndSend {
+    /// Returns whether the message carried a tick.
     fn parse_and_send(
         self,
-        parse: fn(Message) -> Result<Option<InTick>, Error>,
-        tx: UnboundedSender<InTick>,
-    ) -> Result<(), Error>;
+        adapter: &mut dyn ExchangeAdapter,
+        tx: &UnboundedSender<FeedEvent>,
+    ) -> Result<bool, Error>;
 }
 
 impl ParseAndSend for Message {
     fn parse_and_send(
         self,
-        parse: fn(Message) -> Result<Option<InTick>, Error>,
-        tx: UnboundedSender<InTick>,
-    ) -> Result<(), Error>
+        adapter: &mut dyn ExchangeAdapter,
+        tx: &UnboundedSender<FeedEvent>,
+    ) -> Result<bool, Error>
     {
-        parse(self).and_then(|t| {
-            t.map(|tick| {
-                tokio::spawn(async move {
-                    tx.unbounded_send(tick).expect("Failed to send");
-                });
-            });
-            Ok(())
-        })
+        match adapter.parse(self)? {
+            Some(tick) => {
+                tx.unbounded_send(FeedEvent::Tick(tick)).expect("Failed to send");
+                Ok(true)
+            },
+            None => Ok(false),
+        }
     }
-}
\ No newline at end of file
+}
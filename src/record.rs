@@ -0,0 +1,207 @@
+use chrono::{DateTime, Utc};
+use crate::error::Error;
+use crate::orderbook::Exchange;
+use log::info;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tungstenite::Message;
+
+/// A recording file starts with `MAGIC`, followed by one frame per message: the
+/// receive time in microseconds since the Unix epoch (i64), the `Kind` of message
+/// (u8), the length of the payload (u32) and the payload, all big-endian.
+pub(crate) const MAGIC: &[u8; 8] = b"OMREC001";
+
+/// A file is rotated once it grows past this size...
+const MAX_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// ...or has been written to for this long.
+const MAX_FILE_AGE: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum Kind {
+    Text = 0,
+    Binary = 1,
+    Ping = 2,
+    Pong = 3,
+    Close = 4,
+}
+
+/// Writes the messages of one connection of an exchange to
+/// `<dir>/<exchange>-<session start>-<part>.rec`.
+pub(crate) struct Recorder {
+    dir: PathBuf,
+    exchange: Exchange,
+    session: DateTime<Utc>,
+    part: u32,
+    file: File,
+    written: u64,
+    opened: Instant,
+
+    /// When to rotate, `MAX_FILE_SIZE` and `MAX_FILE_AGE` unless testing.
+    max_size: u64,
+    max_age: Duration,
+}
+
+impl Recorder {
+    pub(crate) async fn open(dir: &Path, exchange: &Exchange) -> Result<Recorder, Error> {
+        fs::create_dir_all(dir).await?;
+
+        let session = Utc::now();
+        let file = create(dir, exchange, &session, 0).await?;
+
+        Ok(Recorder {
+            dir: dir.to_path_buf(),
+            exchange: exchange.clone(),
+            session,
+            part: 0,
+            file,
+            written: MAGIC.len() as u64,
+            opened: Instant::now(),
+            max_size: MAX_FILE_SIZE,
+            max_age: MAX_FILE_AGE,
+        })
+    }
+
+    /// Appends a message received now, rotating the file first if it's due.
+    pub(crate) async fn write(&mut self, msg: &Message) -> Result<(), Error> {
+        if self.written > self.max_size || self.opened.elapsed() > self.max_age {
+            self.rotate().await?;
+        }
+
+        let frame = frame(Utc::now(), msg);
+        self.file.write_all(&frame).await?;
+        // waits for the write to land, which tokio otherwise finishes in the background
+        self.file.flush().await?;
+        self.written += frame.len() as u64;
+        Ok(())
+    }
+
+    async fn rotate(&mut self) -> Result<(), Error> {
+        self.file.sync_all().await?;
+        self.part += 1;
+        self.file = create(&self.dir, &self.exchange, &self.session, self.part).await?;
+        self.written = MAGIC.len() as u64;
+        self.opened = Instant::now();
+        Ok(())
+    }
+}
+
+async fn create(
+    dir: &Path,
+    exchange: &Exchange,
+    session: &DateTime<Utc>,
+    part: u32,
+) -> Result<File, Error>
+{
+    let name = format!("{}-{}-{:04}.rec", exchange, session.format("%Y%m%dT%H%M%S%.6fZ"), part);
+    let path = dir.join(name);
+
+    let mut file = OpenOptions::new().create_new(true).append(true).open(&path).await?;
+    file.write_all(MAGIC).await?;
+    info!("Recording {} to {}", exchange, path.display());
+
+    Ok(file)
+}
+
+fn frame(received: DateTime<Utc>, msg: &Message) -> Vec<u8> {
+    let (kind, payload): (Kind, &[u8]) = match msg {
+        Message::Text(s) => (Kind::Text, s.as_bytes()),
+        Message::Binary(b) => (Kind::Binary, b),
+        Message::Ping(b) => (Kind::Ping, b),
+        Message::Pong(b) => (Kind::Pong, b),
+        Message::Close(_) => (Kind::Close, &[]),
+        Message::Frame(f) => (Kind::Binary, f.payload()),
+    };
+
+    let mut frame = Vec::with_capacity(13 + payload.len());
+    frame.extend_from_slice(&received.timestamp_micros().to_be_bytes());
+    frame.push(kind as u8);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// An empty directory of its own for a test.
+    pub(crate) fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ordermaster-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// The recordings in a directory, oldest first.
+    pub(crate) fn recordings(dir: &Path) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = std::fs::read_dir(dir).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[tokio::test]
+    async fn frames_are_written_big_endian_after_the_magic() {
+        let dir = temp_dir("record-format");
+        let before = Utc::now().timestamp_micros();
+        let mut recorder = Recorder::open(&dir, &Exchange::Binance).await.unwrap();
+        recorder.write(&Message::Text("{}".to_string())).await.unwrap();
+        recorder.write(&Message::Ping(vec![1, 2, 3])).await.unwrap();
+        recorder.file.sync_all().await.unwrap();
+        let after = Utc::now().timestamp_micros();
+
+        let files = recordings(&dir);
+        assert_eq!(files.len(), 1);
+        let name = files[0].file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("binance-") && name.ends_with("-0000.rec"), "{}", name);
+
+        let data = std::fs::read(&files[0]).unwrap();
+        assert_eq!(&data[..8], b"OMREC001");
+        let frame = &data[8..];
+        let micros = i64::from_be_bytes(frame[..8].try_into().unwrap());
+        assert!(before <= micros && micros <= after);
+        assert_eq!(frame[8], 0);
+        assert_eq!(&frame[9..13], &[0, 0, 0, 2]);
+        assert_eq!(&frame[13..15], b"{}");
+
+        let frame = &frame[15..];
+        assert_eq!(frame[8], 2);
+        assert_eq!(&frame[9..13], &[0, 0, 0, 3]);
+        assert_eq!(&frame[13..], &[1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn files_rotate_past_their_size() {
+        let dir = temp_dir("record-size");
+        let mut recorder = Recorder::open(&dir, &Exchange::Binance).await.unwrap();
+        // a frame of an 8 byte payload is 21 bytes, so every file holds 2 of them
+        recorder.max_size = MAGIC.len() as u64 + 30;
+        for i in 0..5 {
+            recorder.write(&Message::Text(format!("message{}", i))).await.unwrap();
+        }
+        recorder.file.sync_all().await.unwrap();
+
+        let files = recordings(&dir);
+        let sizes: Vec<u64> = files.iter().map(|f| std::fs::metadata(f).unwrap().len()).collect();
+        assert_eq!(sizes, vec![50, 50, 29]);
+        assert!(files[2].to_str().unwrap().ends_with("-0002.rec"));
+        assert!(files.iter().all(|f| std::fs::read(f).unwrap().starts_with(MAGIC)));
+    }
+
+    #[tokio::test]
+    async fn files_rotate_past_their_age() {
+        let dir = temp_dir("record-age");
+        let mut recorder = Recorder::open(&dir, &Exchange::Binance).await.unwrap();
+        recorder.max_age = Duration::from_millis(500);
+        recorder.write(&Message::Text("first".to_string())).await.unwrap();
+        recorder.write(&Message::Text("second".to_string())).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        recorder.write(&Message::Text("third".to_string())).await.unwrap();
+
+        assert_eq!(recordings(&dir).len(), 2);
+    }
+}
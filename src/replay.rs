@@ -0,0 +1,245 @@
+use chrono::{DateTime, TimeZone, Utc};
+use crate::orderbook::Exchange;
+use crate::record::{Kind, MAGIC};
+use crate::websocket::{Replay as ReplayStream, WsStream};
+use futures::channel::mpsc;
+use futures::SinkExt;
+use log::{info, warn};
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::time::Instant;
+use tungstenite::Message;
+
+/// Messages read ahead of the replay.
+const BUFFER: usize = 1024;
+
+/// Where to replay the traffic of the exchanges from, in place of their websockets.
+#[derive(Debug, Clone)]
+pub struct Replay {
+    /// Directory holding the files written by `--record`.
+    pub dir: PathBuf,
+
+    /// How many times faster than recorded to replay, as fast as possible when `None`.
+    pub speed: Option<f64>,
+}
+
+impl Replay {
+    /// Starts replaying the recordings of an exchange, oldest first, into a stream
+    /// standing in for its websocket.
+    pub(crate) fn open(&self, exchange: &Exchange) -> WsStream {
+        let (tx, rx) = mpsc::channel(BUFFER);
+        tokio::spawn(replay(self.clone(), exchange.clone(), tx));
+        WsStream::Replay(ReplayStream::new(rx))
+    }
+}
+
+type Sender = mpsc::Sender<Result<Message, tungstenite::Error>>;
+
+async fn replay(replay: Replay, exchange: Exchange, mut tx: Sender) {
+    let files = match files(&replay.dir, &exchange) {
+        Ok(files) => files,
+        Err(e) => {
+            let _ = tx.send(Err(e.into())).await;
+            return
+        },
+    };
+    if files.is_empty() {
+        warn!("No recordings of {} in {}", exchange, replay.dir.display());
+    }
+
+    // maps the recorded time of the first message to the start of the replay
+    let mut start: Option<(DateTime<Utc>, Instant)> = None;
+
+    for path in files {
+        info!("Replaying {}", path.display());
+
+        let frames = match tokio::fs::read(&path).await.and_then(|data| frames(&data)) {
+            Ok(frames) => frames,
+            Err(e) => {
+                let _ = tx.send(Err(e.into())).await;
+                return
+            },
+        };
+
+        for (received, msg) in frames {
+            if let Some(speed) = replay.speed {
+                let (first, started) = *start.get_or_insert((received, Instant::now()));
+                let offset = (received - first).to_std().unwrap_or_default();
+                tokio::time::sleep_until(started + offset.div_f64(speed)).await;
+            }
+
+            if tx.send(Ok(msg)).await.is_err() {
+                // the connection was dropped
+                return
+            }
+        }
+    }
+
+    info!("Replay of {} finished", exchange);
+}
+
+/// The recordings of an exchange, which sort by the time they were started.
+fn files(dir: &Path, exchange: &Exchange) -> io::Result<Vec<PathBuf>> {
+    let prefix = format!("{}-", exchange);
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+            name.starts_with(&prefix) && name.ends_with(".rec")
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// Reads the frames of a recording. A frame cut short, e.g. by a crash while
+/// recording, ends the recording.
+fn frames(data: &[u8]) -> io::Result<Vec<(DateTime<Utc>, Message)>> {
+    let rest = data.strip_prefix(MAGIC.as_slice())
+        .ok_or_else(|| invalid("not a recording"))?;
+
+    let mut frames = vec![];
+    let mut rest = rest;
+    while !rest.is_empty() {
+        if rest.len() < 13 {
+            warn!("Recording ends with a partial frame");
+            break
+        }
+        let (header, tail) = rest.split_at(13);
+        let micros = i64::from_be_bytes(header[0..8].try_into().unwrap());
+        let kind = header[8];
+        let len = u32::from_be_bytes(header[9..13].try_into().unwrap()) as usize;
+
+        if tail.len() < len {
+            warn!("Recording ends with a partial frame");
+            break
+        }
+        let (payload, tail) = tail.split_at(len);
+        rest = tail;
+
+        let received = Utc.timestamp_micros(micros).single()
+            .ok_or_else(|| invalid("invalid receive time"))?;
+        frames.push((received, message(kind, payload)?));
+    }
+
+    Ok(frames)
+}
+
+fn message(kind: u8, payload: &[u8]) -> io::Result<Message> {
+    let msg = match kind {
+        k if k == Kind::Text as u8 => {
+            let text = String::from_utf8(payload.to_vec()).map_err(|_| invalid("text is not utf-8"))?;
+            Message::Text(text)
+        },
+        k if k == Kind::Binary as u8 => Message::Binary(payload.to_vec()),
+        k if k == Kind::Ping as u8 => Message::Ping(payload.to_vec()),
+        k if k == Kind::Pong as u8 => Message::Pong(payload.to_vec()),
+        k if k == Kind::Close as u8 => Message::Close(None),
+        k => return Err(invalid(&format!("unknown kind of message {}", k))),
+    };
+    Ok(msg)
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::tests::{recordings, temp_dir};
+    use crate::record::Recorder;
+    use futures::StreamExt;
+    use std::io::Write;
+    use std::time::Duration;
+
+    /// Records a text, a ping and a binary message `gap` apart.
+    async fn record(dir: &Path, gap: Duration) {
+        let mut recorder = Recorder::open(dir, &Exchange::Kraken).await.unwrap();
+        recorder.write(&Message::Text("first".to_string())).await.unwrap();
+        tokio::time::sleep(gap).await;
+        recorder.write(&Message::Ping(vec![1])).await.unwrap();
+        tokio::time::sleep(gap).await;
+        recorder.write(&Message::Binary(vec![2, 3])).await.unwrap();
+    }
+
+    /// Reads `n` messages, returning them and how long it took.
+    async fn read(stream: &mut WsStream, n: usize) -> (Vec<Message>, Duration) {
+        let started = Instant::now();
+        let mut messages = vec![];
+        for _ in 0..n {
+            let msg = tokio::time::timeout(Duration::from_secs(5), stream.next()).await
+                .expect("the replay stalled")
+                .expect("the replay ended")
+                .unwrap();
+            messages.push(msg);
+        }
+        (messages, started.elapsed())
+    }
+
+    fn recorded() -> Vec<Message> {
+        vec![Message::Text("first".to_string()), Message::Ping(vec![1]), Message::Binary(vec![2, 3])]
+    }
+
+    #[tokio::test]
+    async fn replays_the_recording_in_order_as_fast_as_possible() {
+        let dir = temp_dir("replay-fast");
+        record(&dir, Duration::from_millis(200)).await;
+
+        let mut stream = Replay { dir, speed: None }.open(&Exchange::Kraken);
+        let (messages, took) = read(&mut stream, 3).await;
+        assert_eq!(messages, recorded());
+        assert!(took < Duration::from_millis(200), "{:?}", took);
+    }
+
+    #[tokio::test]
+    async fn replays_the_recording_at_its_pace() {
+        let dir = temp_dir("replay-paced");
+        record(&dir, Duration::from_millis(200)).await;
+
+        let mut stream = Replay { dir, speed: Some(2.0) }.open(&Exchange::Kraken);
+        let (messages, took) = read(&mut stream, 3).await;
+        assert_eq!(messages, recorded());
+        // 400ms recorded, twice as fast
+        assert!(took >= Duration::from_millis(190) && took < Duration::from_millis(400), "{:?}", took);
+    }
+
+    #[tokio::test]
+    async fn a_partial_last_frame_ends_the_replay() {
+        let dir = temp_dir("replay-partial");
+        record(&dir, Duration::ZERO).await;
+        // a frame announcing 100 bytes, cut short after 5
+        let path = &recordings(&dir)[0];
+        let mut file = std::fs::OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(&0i64.to_be_bytes()).unwrap();
+        file.write_all(&[Kind::Text as u8, 0, 0, 0, 100, b'p', b'a', b'r', b't', b'i']).unwrap();
+        drop(file);
+
+        let mut stream = Replay { dir, speed: None }.open(&Exchange::Kraken);
+        let (messages, _) = read(&mut stream, 3).await;
+        assert_eq!(messages, recorded());
+        // silent from then on rather than failing, like an idle connection
+        assert!(tokio::time::timeout(Duration::from_millis(200), stream.next()).await.is_err());
+    }
+
+    #[test]
+    fn frames_stop_at_a_partial_header() {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&1_650_000_000_000_000i64.to_be_bytes());
+        data.extend_from_slice(&[Kind::Text as u8, 0, 0, 0, 2]);
+        data.extend_from_slice(b"{}");
+        data.extend_from_slice(&[0, 0, 0]);
+
+        let frames = frames(&data).unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].0.timestamp_micros(), 1_650_000_000_000_000);
+        assert_eq!(frames[0].1, Message::Text("{}".to_string()));
+    }
+
+    #[test]
+    fn frames_need_the_magic() {
+        assert!(frames(b"OMREC000").is_err());
+    }
+}
+
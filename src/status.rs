@@ -0,0 +1,105 @@
+use crate::error::Error;
+use crate::orderbook::Exchange;
+use crate::ordermaster::FeedEvent;
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ConnectionState {
+    Connecting,
+    Connected,
+    Disconnected,
+}
+
+/// Health of the feed of one exchange.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct FeedStatus {
+    pub(crate) state: ConnectionState,
+    pub(crate) last_message: Option<DateTime<Utc>>,
+    pub(crate) reconnects: u64,
+    pub(crate) messages: u64,
+
+    /// Messages per second over the last sampling period.
+    pub(crate) message_rate: f64,
+    pub(crate) parse_errors: u64,
+    pub(crate) last_error: Option<String>,
+
+    /// Value of `messages` when the rate was last sampled.
+    sampled_messages: u64,
+}
+
+impl FeedStatus {
+    fn new() -> FeedStatus {
+        FeedStatus {
+            state: ConnectionState::Connecting,
+            last_message: None,
+            reconnects: 0,
+            messages: 0,
+            message_rate: 0.0,
+            parse_errors: 0,
+            last_error: None,
+            sampled_messages: 0,
+        }
+    }
+}
+
+/// Health of the feeds of all exchanges, as seen by the `Connector`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Status {
+    pub(crate) feeds: BTreeMap<Exchange, FeedStatus>,
+}
+
+impl Status {
+    pub(crate) fn new() -> Status {
+        Status { feeds: BTreeMap::new() }
+    }
+
+    /// Accounts for an event of a feed. Returns whether the connection state changed,
+    /// which subscribers should hear about right away.
+    pub(crate) fn update(&mut self, event: &FeedEvent) -> bool {
+        match event {
+            FeedEvent::Connecting(exchange) => {
+                let feed = self.feed(exchange);
+                if feed.state == ConnectionState::Disconnected {
+                    feed.reconnects += 1;
+                }
+                feed.state = ConnectionState::Connecting;
+                true
+            },
+            FeedEvent::Connected(exchange) => {
+                self.feed(exchange).state = ConnectionState::Connected;
+                true
+            },
+            FeedEvent::Received(exchange) => {
+                let feed = self.feed(exchange);
+                feed.messages += 1;
+                feed.last_message = Some(Utc::now());
+                false
+            },
+            FeedEvent::Tick(_) => false,
+            FeedEvent::Down(e) => {
+                let feed = self.feed(&e.exchange);
+                if let Error::BadData(_) = e.error {
+                    feed.parse_errors += 1;
+                }
+                feed.last_error = Some(format!("{:?}", e.error));
+                feed.state = ConnectionState::Disconnected;
+                true
+            },
+        }
+    }
+
+    /// Computes the message rate of every feed since the previous sample.
+    pub(crate) fn sample(&mut self, elapsed: Duration) {
+        for feed in self.feeds.values_mut() {
+            let messages = feed.messages - feed.sampled_messages;
+            feed.message_rate = messages as f64 / elapsed.as_secs_f64();
+            feed.sampled_messages = feed.messages;
+        }
+    }
+
+    fn feed(&mut self, exchange: &Exchange) -> &mut FeedStatus {
+        self.feeds.entry(exchange.clone()).or_insert_with(FeedStatus::new)
+    }
+}
@@ -1,25 +1,105 @@
 use crate::error::Error;
-use futures::{SinkExt, StreamExt};
-use log::info;
+use futures::channel::mpsc;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use log::{info, warn};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
 use tokio::net::TcpStream;
+use tokio::time::timeout;
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 use tungstenite::Message;
 use url::Url;
 
-pub(crate) type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+/// The connection to an exchange, either a live websocket or a replay of one.
+pub(crate) enum WsStream {
+    Live(Box<WebSocketStream<MaybeTlsStream<TcpStream>>>),
+    Replay(Replay),
+}
 
 pub(crate) async fn connect(s: &str) -> Result<WsStream, Error> {
     let url = Url::parse(s).unwrap();
     let (ws_stream, _) =
         tokio_tungstenite::connect_async(url).await?;
     info!("Successfully connected to {}", s);
-    Ok(ws_stream)
+    Ok(WsStream::Live(Box::new(ws_stream)))
+}
+
+/// Yields recorded messages and swallows whatever is sent. Once the recording is
+/// over it stays silent, like an idle connection, until it's closed.
+pub(crate) struct Replay {
+    rx: mpsc::Receiver<Result<Message, tungstenite::Error>>,
+    closed: bool,
+}
+
+impl Replay {
+    pub(crate) fn new(rx: mpsc::Receiver<Result<Message, tungstenite::Error>>) -> Self {
+        Replay { rx, closed: false }
+    }
+}
+
+impl Stream for WsStream {
+    type Item = Result<Message, tungstenite::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.get_mut() {
+            WsStream::Live(ws_stream) => Pin::new(ws_stream).poll_next(cx),
+            WsStream::Replay(replay) if replay.closed => Poll::Ready(None),
+            WsStream::Replay(replay) => match Pin::new(&mut replay.rx).poll_next(cx) {
+                Poll::Ready(None) => Poll::Pending,
+                poll => poll,
+            },
+        }
+    }
 }
 
+impl Sink<Message> for WsStream {
+    type Error = tungstenite::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match self.get_mut() {
+            WsStream::Live(ws_stream) => Pin::new(ws_stream).poll_ready(cx),
+            WsStream::Replay(_) => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        match self.get_mut() {
+            WsStream::Live(ws_stream) => Pin::new(ws_stream).start_send(item),
+            WsStream::Replay(replay) => {
+                replay.closed |= item.is_close();
+                Ok(())
+            },
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match self.get_mut() {
+            WsStream::Live(ws_stream) => Pin::new(ws_stream).poll_flush(cx),
+            WsStream::Replay(_) => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match self.get_mut() {
+            WsStream::Live(ws_stream) => Pin::new(ws_stream).poll_close(cx),
+            WsStream::Replay(replay) => {
+                replay.closed = true;
+                Poll::Ready(Ok(()))
+            },
+        }
+    }
+}
+
+/// Gives up on the Close-handshake if the server doesn't answer in time, which is
+/// common when closing a connection that already dropped.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub(crate) async fn close(ws_stream: &mut WsStream) {
     let _ = ws_stream.send(Message::Close(None)).await;
-    let close = ws_stream.next().await;
-    info!("server close msg: {:?}", close);
-    assert!(ws_stream.next().await.is_none());
-    let _ = ws_stream.close(None).await;
+    match timeout(CLOSE_TIMEOUT, ws_stream.next()).await {
+        Ok(close) => info!("server close msg: {:?}", close),
+        Err(_) => warn!("server did not answer the close handshake"),
+    }
+    let _ = ws_stream.close().await;
 }
\ No newline at end of file
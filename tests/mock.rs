@@ -0,0 +1,198 @@
+use futures::StreamExt;
+use keyrock_orders::mock::{self, Scenario, Script};
+use keyrock_orders::ordermaster::{self, ExchangeSettings, Settings};
+use keyrock_orders::proto::orderbook_aggregator_client::OrderbookAggregatorClient;
+use keyrock_orders::proto::{BookRequest, ConnectionState, Empty, FeedStatus, Summary};
+use keyrock_orders::{BookStream, Error, Exchange};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tonic::transport::Channel;
+use tonic::Streaming;
+
+/// Order book messages the stand-ins send before the scenario kicks in.
+const AFTER: usize = 5;
+
+/// Starts the stand-in of an exchange on an ephemeral port behind a proxy counting
+/// the connections to it, returns the url of the proxy and the count.
+async fn stand_in(exchange: Exchange, scenario: Scenario) -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let script = Script { scenario, interval: Duration::from_millis(20), after: AFTER };
+    tokio::spawn(mock::serve(listener, exchange, script));
+
+    let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", proxy.local_addr().unwrap());
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counted = connections.clone();
+    tokio::spawn(async move {
+        while let Ok((mut client, _)) = proxy.accept().await {
+            counted.fetch_add(1, Ordering::SeqCst);
+            let mut server = TcpStream::connect(addr).await.unwrap();
+            tokio::spawn(async move {
+                let _ = tokio::io::copy_bidirectional(&mut client, &mut server).await;
+            });
+        }
+    });
+    (url, connections)
+}
+
+/// An address which was free a moment ago.
+fn free_addr() -> SocketAddr {
+    std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+/// Aggregates ETH/BTC from the stand-in of a single exchange, whose connections
+/// are counted.
+async fn settings(exchange: Exchange, scenario: Scenario) -> (Settings, Arc<AtomicUsize>) {
+    let (url, connections) = stand_in(exchange.clone(), scenario).await;
+    // nothing listens there, so the symbols stream unchecked rather than looked up live
+    let rest_url = Some(format!("http://{}", free_addr()));
+    let mut exchanges = BTreeMap::new();
+    let settings = ExchangeSettings { url, rest_url, stream: BookStream::Partial, stale_after: None, precision: None, json: None };
+    exchanges.insert(exchange, settings);
+    let settings = Settings {
+        symbols: vec!["ETH/BTC".parse().unwrap()],
+        depth: 10,
+        grpc_addr: free_addr(),
+        metrics_addr: None,
+        exchanges,
+        bbo: false,
+        record: None,
+        replay: None,
+    };
+    (settings, connections)
+}
+
+/// Runs the server and connects to it.
+async fn serve(settings: Settings) -> OrderbookAggregatorClient<Channel> {
+    let grpc_addr = settings.grpc_addr;
+    tokio::spawn(ordermaster::run(settings));
+
+    loop {
+        match OrderbookAggregatorClient::connect(format!("http://{}", grpc_addr)).await {
+            Ok(client) => return client,
+            Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    }
+}
+
+/// Runs the server and watches the status of the feed of `exchange` until
+/// `done` holds, returning every status seen on the way.
+async fn watch(settings: Settings, exchange: Exchange, done: impl Fn(&FeedStatus) -> bool) -> Vec<FeedStatus> {
+    let mut client = serve(settings).await;
+    let mut statuses = client.watch_status(Empty {}).await.unwrap().into_inner();
+
+    let mut seen = vec![];
+    let watching = async {
+        while let Some(status) = statuses.next().await {
+            let feed = status.unwrap().feeds.into_iter().find(|f| f.exchange == exchange.to_string());
+            if let Some(feed) = feed {
+                seen.push(feed.clone());
+                if done(&feed) {
+                    return
+                }
+            }
+        }
+        panic!("the status stream ended");
+    };
+    tokio::time::timeout(Duration::from_secs(15), watching).await
+        .unwrap_or_else(|_| panic!("{} didn't get there, saw {:#?}", exchange, seen));
+    seen
+}
+
+fn reconnected(feed: &FeedStatus) -> bool {
+    feed.reconnects > 0 && feed.state == ConnectionState::Connected as i32
+}
+
+#[tokio::test]
+async fn bitstamp_rejecting_the_subscription_stops_the_server() {
+    let (settings, _) = settings(Exchange::Bitstamp, Scenario::SubscriptionError).await;
+    let res = tokio::time::timeout(Duration::from_secs(10), ordermaster::run(settings)).await
+        .expect("the server kept running");
+    assert!(matches!(res, Err(Error::Rejected { exchange: Exchange::Bitstamp, .. })), "{:?}", res);
+}
+
+#[tokio::test]
+async fn binance_not_confirming_the_subscription_stops_the_server() {
+    let (settings, _) = settings(Exchange::Binance, Scenario::SubscriptionError).await;
+    // Binance is given 15 seconds to send anything
+    let res = tokio::time::timeout(Duration::from_secs(30), ordermaster::run(settings)).await
+        .expect("the server kept running");
+    assert!(matches!(res, Err(Error::Unconfirmed { exchange: Exchange::Binance, .. })), "{:?}", res);
+}
+
+#[tokio::test]
+async fn abrupt_close_reconnects() {
+    let (settings, _) = settings(Exchange::Bitstamp, Scenario::AbruptClose).await;
+    let seen = watch(settings, Exchange::Bitstamp, reconnected).await;
+    assert!(seen.last().unwrap().last_error.starts_with("BadConnection"), "{:#?}", seen);
+}
+
+#[tokio::test]
+async fn malformed_message_reconnects() {
+    let (settings, _) = settings(Exchange::Bitstamp, Scenario::Malformed).await;
+    let seen = watch(settings, Exchange::Bitstamp, reconnected).await;
+    let last = seen.last().unwrap();
+    assert!(last.parse_errors > 0);
+    assert!(last.last_error.starts_with("BadData"), "{}", last.last_error);
+}
+
+#[tokio::test]
+async fn requested_reconnect_keeps_the_exchange_up() {
+    let (settings, connections) = settings(Exchange::Bitstamp, Scenario::RequestReconnect).await;
+    // well past the request, the new connection streaming
+    let done = |f: &FeedStatus| connections.load(Ordering::SeqCst) > 1 && f.messages > 4 * AFTER as u64 + 20;
+    let seen = watch(settings, Exchange::Bitstamp, done).await;
+    assert_up(&seen);
+}
+
+#[tokio::test]
+async fn missing_heartbeat_resubscribes_and_keeps_the_exchange_up() {
+    let (settings, connections) = settings(Exchange::Coinbase, Scenario::MissingHeartbeat).await;
+    // Coinbase is given 5 seconds between heartbeats
+    let seen = watch(settings, Exchange::Coinbase, |_| connections.load(Ordering::SeqCst) > 1).await;
+    assert_up(&seen);
+}
+
+#[tokio::test]
+async fn slow_consumer_skips_to_newer_books_and_the_feed_keeps_up() {
+    let (settings, _) = settings(Exchange::Bitstamp, Scenario::SlowConsumer).await;
+    let mut client = serve(settings).await;
+    let mut summaries = client.book_summary(BookRequest::default()).await.unwrap().into_inner();
+    // the book is empty until Bitstamp streams
+    while next(&mut summaries).await.bids.is_empty() {}
+
+    let mut sequences = vec![];
+    for _ in 0..10 {
+        let summary = next(&mut summaries).await;
+        sequences.push(summary.sequence);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    assert!(sequences.windows(2).all(|w| w[1] > w[0]), "{:?}", sequences);
+    // the updates in between were left out
+    assert!(sequences[9] - sequences[1] > 8 * 10, "{:?}", sequences);
+
+    let status = client.get_status(Empty {}).await.unwrap().into_inner();
+    let feed = status.feeds.into_iter().find(|f| f.exchange == "bitstamp").unwrap();
+    assert!(feed.messages > sequences[9], "{:#?}", feed);
+    assert_up(&[feed]);
+}
+
+/// The next summary, which must come within 5 seconds.
+async fn next(summaries: &mut Streaming<Summary>) -> Summary {
+    tokio::time::timeout(Duration::from_secs(5), summaries.next()).await
+        .expect("no summary").unwrap().unwrap()
+}
+
+/// Asserts the feed was never reported down.
+fn assert_up(seen: &[FeedStatus]) {
+    assert!(seen.iter().all(|f| f.state != ConnectionState::Disconnected as i32), "{:#?}", seen);
+    let last = seen.last().unwrap();
+    assert_eq!(last.reconnects, 0);
+    assert_eq!(last.last_error, "");
+    assert_eq!(last.state, ConnectionState::Connected as i32);
+}